@@ -1,18 +1,14 @@
 use chrono::prelude::DateTime;
 use chrono::Utc;
-use pcap::Device;
-use std::io::stdin;
-use std::iter::FromIterator;
 use std::path;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, UNIX_EPOCH};
 use structopt::StructOpt;
-use warp::Filter;
-use zwift_capture::{Player, ZwiftCapture};
-use zwift_watcher::server::{handlers, models, Routes};
-use zwift_watcher::{PlayerData, PlayerGroup, World, PLAYER_GROUP_CAPACITY};
+use zwift_capture::ZwiftCapture;
+use zwift_watcher::server::Routes;
+use zwift_watcher::World;
 
 const TICK: i64 = 1000;
 
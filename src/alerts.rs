@@ -0,0 +1,431 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::Receiver;
+use zwift_capture::Player;
+use crate::World;
+use crate::spatial::{self, ROAD_WIDTH};
+
+
+const ALERT_TICK: i64 = 1000; // evaluate once per second of world time
+const WEBHOOK_RETRIES: u32 = 3;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+pub const ALERT_CHANNEL_CAPACITY: usize = 100;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AlertRule {
+    /// rider is more than `gap` meters behind the first rider of watch group on the same road
+    Dropped { gap: i32 },
+    HeartrateAbove { threshold: i32 },
+    /// power above `watts` for at least `seconds`
+    PowerAbove { watts: i32, seconds: i64 },
+    /// rider removed from world as outdated
+    Disconnected
+}
+
+impl AlertRule {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlertRule::Dropped { .. } => "dropped",
+            AlertRule::HeartrateAbove { .. } => "heartrate_above",
+            AlertRule::PowerAbove { .. } => "power_above",
+            AlertRule::Disconnected => "disconnected"
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct AlertConfig {
+    pub webhooks: Vec<String>,
+    pub rules: Vec<AlertRule>
+}
+
+impl AlertConfig {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        serde_json::from_str(&content).map_err(|err| err.to_string())
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Alert {
+    pub rule: String,
+    pub player_id: i32,
+    pub world_time: i64,
+    pub value: f64,
+    pub message: String
+}
+
+
+#[derive(Debug,Default)]
+pub struct AlertEngine {
+    rules: Vec<AlertRule>,
+    last_evaluated: i64,
+    // (rule index, player id) with condition currently true, alert sent only on activation
+    active: HashSet<(usize, i32)>,
+    // (rule index, player id) -> world time when power went above threshold
+    power_above_since: HashMap<(usize, i32), i64>
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>) -> Self {
+        AlertEngine {
+            rules,
+            ..AlertEngine::default()
+        }
+    }
+
+    fn update_state(&mut self, key: (usize, i32), condition: bool) -> bool {
+        if condition {
+            self.active.insert(key)
+        } else {
+            self.active.remove(&key);
+            false
+        }
+    }
+
+    /// Check rules for watch group, returns only newly raised alerts
+    pub fn evaluate(&mut self, world: &World) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        if world.world_time - self.last_evaluated < ALERT_TICK {
+            return alerts;
+        }
+        self.last_evaluated = world.world_time;
+        // riders removed from watch group raise alerts again when added back
+        self.active.retain(|&(_, id)| world.group_to_watch.has_player(id));
+        self.power_above_since.retain(|&(_, id), _| world.group_to_watch.has_player(id));
+
+        let players = world.get_group_to_watch_data(false);
+        let rules = self.rules.clone();
+
+        for player in players.iter() {
+            for (ix, rule) in rules.iter().enumerate() {
+                let key = (ix, player.id);
+                let alert = match rule {
+                    AlertRule::Dropped { gap } => {
+                        let behind = gap_to_front(world, player, &players);
+                        match self.update_state(key, behind > *gap as f64) {
+                            true => Some((behind, format!("Rider {} dropped, {:.0}m behind", player.id, behind))),
+                            false => None
+                        }
+                    },
+                    AlertRule::HeartrateAbove { threshold } => {
+                        let heartrate = player.heartrate as i32;
+                        match self.update_state(key, heartrate > *threshold) {
+                            true => Some((heartrate as f64, format!("Rider {} heart rate {} above {}", player.id, heartrate, threshold))),
+                            false => None
+                        }
+                    },
+                    AlertRule::PowerAbove { watts, seconds } => {
+                        let power = player.power as i32;
+                        let sustained = if power > *watts {
+                            let since = *self.power_above_since.entry(key).or_insert(player.world_time);
+                            player.world_time - since >= seconds * 1000
+                        } else {
+                            self.power_above_since.remove(&key);
+                            false
+                        };
+                        match self.update_state(key, sustained) {
+                            true => Some((power as f64, format!("Rider {} power above {}W for {}s", player.id, watts, seconds))),
+                            false => None
+                        }
+                    },
+                    AlertRule::Disconnected => None
+                };
+                if let Some((value, message)) = alert {
+                    alerts.push(Alert {
+                        rule: String::from(rule.name()),
+                        player_id: player.id,
+                        world_time: player.world_time,
+                        value,
+                        message
+                    });
+                }
+            }
+        }
+        alerts
+    }
+
+    /// Alerts for watched players going to be removed from world as outdated
+    pub fn players_disconnected(&mut self, world: &World, player_ids: &[i32]) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for &player_id in player_ids.iter() {
+            self.active.retain(|&(_, id)| id != player_id);
+            self.power_above_since.retain(|&(_, id), _| id != player_id);
+            if !world.group_to_watch.has_player(player_id) {
+                continue;
+            }
+            let last_seen = world.get_player_data(player_id).map(|player| player.world_time).unwrap_or(world.world_time);
+            for rule in self.rules.iter().filter(|&rule| *rule == AlertRule::Disconnected) {
+                alerts.push(Alert {
+                    rule: String::from(rule.name()),
+                    player_id,
+                    world_time: world.world_time,
+                    value: (world.world_time - last_seen) as f64 / 1000.,
+                    message: format!("Rider {} disconnected", player_id)
+                });
+            }
+        }
+        alerts
+    }
+}
+
+/// Distance along direction of travel to the furthest rider ahead on the same road,
+/// `distance` counters can't be compared as riders start them at different places
+fn gap_to_front(world: &World, player: &Player, players: &[Player]) -> f64 {
+    let direction = match world.get_player_data(player.id).and_then(|data| data.get_direction(player.world_time)) {
+        Some(direction) => direction,
+        _ => return 0.
+    };
+    players.iter()
+        .filter(|other| other.id != player.id)
+        .map(|other| spatial::road_gap(player, other, direction))
+        .filter(|&(_, side)| side <= ROAD_WIDTH)
+        .map(|(along, _)| along)
+        .fold(0., f64::max)
+}
+
+
+pub struct WebhookSender {
+    urls: Vec<String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+    retry_delay: Duration
+}
+
+impl WebhookSender {
+    pub fn new(urls: Vec<String>) -> Self {
+        WebhookSender {
+            urls,
+            client: Client::builder().build(
+                HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build()
+            ),
+            retry_delay: Duration::from_millis(500)
+        }
+    }
+
+    async fn post(&self, url: &str, body: &str) -> Result<(), String> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("content-type", "application/json")
+            .body(Body::from(String::from(body)))
+            .map_err(|err| err.to_string())?;
+        let response = tokio::time::timeout(WEBHOOK_TIMEOUT, self.client.request(request)).await
+            .map_err(|_| String::from("Webhook request timed out"))?
+            .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("Webhook response status: {}", response.status()));
+        }
+        Ok(())
+    }
+
+    pub async fn send(&self, alert: &Alert) -> Result<(), String> {
+        let body = serde_json::to_string(alert).map_err(|err| err.to_string())?;
+        let mut result = Ok(());
+        for url in self.urls.iter() {
+            let mut attempt = 0;
+            loop {
+                attempt += 1;
+                match self.post(url, &body).await {
+                    Ok(_) => break,
+                    Err(err) if attempt >= WEBHOOK_RETRIES => {
+                        result = Err(format!("{}: {}", url, err));
+                        break;
+                    },
+                    Err(_) => tokio::time::sleep(self.retry_delay * attempt).await
+                }
+            }
+        }
+        result
+    }
+
+    pub async fn run(self, mut receiver: Receiver<Alert>) {
+        while let Some(alert) = receiver.recv().await {
+            if let Err(err) = self.send(&alert).await {
+                println!("Webhook error: {}", err);
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    fn get_world(heartrate: i32, power: i32) -> World {
+        let mut world = World::new();
+        let mut leader = get_player_instance();
+        leader.id = 1;
+        leader.x = 1000.;
+        // distance counter started before rider joined, ignored for gaps
+        leader.distance = 100;
+        let mut rider = get_player_instance();
+        rider.id = 2;
+        rider.x = 900.;
+        rider.distance = 5000;
+        rider.heartrate = heartrate as _;
+        rider.power = power as _;
+        // previous second to know direction of travel
+        for player in [leader, rider].iter() {
+            let mut previous = player.clone();
+            previous.world_time -= 1000;
+            previous.x -= 10.;
+            world.push_player(previous);
+            world.push_player(player.clone());
+        }
+        world.add_player_to_watch(1);
+        world.add_player_to_watch(2);
+        world
+    }
+
+    fn push_update(world: &mut World, world_time: i64, heartrate: i32, power: i32) {
+        for id in 1..3 {
+            let mut player = world.get_player_data(id).unwrap().get_latest().unwrap();
+            player.x += (world_time - player.world_time) as f64 / 100.;
+            player.world_time = world_time;
+            if id == 2 {
+                player.heartrate = heartrate as _;
+                player.power = power as _;
+            }
+            world.push_player(player);
+        }
+    }
+
+    #[test]
+    fn alert_rules_deduplicated() {
+        let mut world = get_world(190, 100);
+        let mut engine = AlertEngine::new(vec![
+            AlertRule::HeartrateAbove { threshold: 180 },
+            AlertRule::Dropped { gap: 50 }
+        ]);
+        let alerts = engine.evaluate(&world);
+        assert_eq!(alerts.len(), 2);
+        assert!(alerts.iter().all(|alert| alert.player_id == 2));
+
+        let world_time = world.world_time;
+        push_update(&mut world, world_time + ALERT_TICK, 190, 100);
+        assert!(engine.evaluate(&world).is_empty());
+
+        push_update(&mut world, world_time + 2 * ALERT_TICK, 150, 100);
+        push_update(&mut world, world_time + 3 * ALERT_TICK, 150, 100);
+        assert!(engine.evaluate(&world).is_empty());
+        push_update(&mut world, world_time + 4 * ALERT_TICK, 185, 100);
+        let alerts = engine.evaluate(&world);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].rule, "heartrate_above");
+    }
+
+    #[test]
+    fn alert_dropped_on_same_road() {
+        let mut world = get_world(100, 100);
+        let mut engine = AlertEngine::new(vec![AlertRule::Dropped { gap: 50 }]);
+        let alerts = engine.evaluate(&world);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].player_id, 2);
+        assert_eq!(alerts[0].value, 100.);
+
+        // leader on parallel road isn't counted
+        let mut leader = world.get_player_data(1).unwrap().get_latest().unwrap();
+        let mut rider = world.get_player_data(2).unwrap().get_latest().unwrap();
+        leader.world_time += ALERT_TICK;
+        leader.x += 10.;
+        leader.y += 200.;
+        rider.world_time += ALERT_TICK;
+        rider.x += 10.;
+        world.push_player(leader);
+        world.push_player(rider);
+        assert!(engine.evaluate(&world).is_empty());
+        assert!(!engine.active.contains(&(0, 2)));
+    }
+
+    #[test]
+    fn alert_state_pruned_on_watch_leave() {
+        let mut world = get_world(190, 100);
+        let mut engine = AlertEngine::new(vec![AlertRule::HeartrateAbove { threshold: 180 }]);
+        assert_eq!(engine.evaluate(&world).len(), 1);
+        world.remove_player_from_watch(2);
+        let world_time = world.world_time;
+        push_update(&mut world, world_time + ALERT_TICK, 190, 100);
+        assert!(engine.evaluate(&world).is_empty());
+        assert!(engine.active.is_empty());
+        // alert raised again after rider is back
+        world.add_player_to_watch(2);
+        push_update(&mut world, world_time + 2 * ALERT_TICK, 190, 100);
+        assert_eq!(engine.evaluate(&world).len(), 1);
+    }
+
+    #[test]
+    fn alert_power_above_for_time() {
+        let mut world = get_world(100, 500);
+        let mut engine = AlertEngine::new(vec![AlertRule::PowerAbove { watts: 400, seconds: 2 }]);
+        let world_time = world.world_time;
+        assert!(engine.evaluate(&world).is_empty());
+        push_update(&mut world, world_time + ALERT_TICK, 100, 500);
+        assert!(engine.evaluate(&world).is_empty());
+        push_update(&mut world, world_time + 2 * ALERT_TICK, 100, 500);
+        let alerts = engine.evaluate(&world);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].value, 500.);
+    }
+
+    #[test]
+    fn alert_disconnected() {
+        let world = get_world(100, 100);
+        let mut engine = AlertEngine::new(vec![AlertRule::Disconnected]);
+        let alerts = engine.players_disconnected(&world, &[2, 3]);
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].player_id, 2);
+    }
+
+    #[test]
+    fn alert_config_parse() {
+        let config: AlertConfig = serde_json::from_str(r#"{
+            "webhooks": ["http://localhost/hook"],
+            "rules": [{"type": "power_above", "watts": 400, "seconds": 10}, {"type": "disconnected"}]
+        }"#).unwrap();
+        assert_eq!(config.rules[0], AlertRule::PowerAbove { watts: 400, seconds: 10 });
+        assert_eq!(config.rules[1], AlertRule::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn webhook_retry() {
+        let calls = Arc::new(Mutex::new(0));
+        let calls_server = calls.clone();
+        let route = warp::post()
+            .and(warp::body::json())
+            .map(move |alert: Alert| {
+                let mut calls = calls_server.lock().unwrap();
+                *calls += 1;
+                assert_eq!(alert.player_id, 2);
+                // fail first attempt
+                let status = match *calls {
+                    1 => warp::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    _ => warp::http::StatusCode::OK
+                };
+                warp::reply::with_status(warp::reply(), status)
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut sender = WebhookSender::new(vec![format!("http://{}/", address)]);
+        sender.retry_delay = Duration::from_millis(1);
+        let alert = Alert { rule: String::from("disconnected"), player_id: 2, world_time: 0, value: 0., message: String::new() };
+        assert!(sender.send(&alert).await.is_ok());
+        assert_eq!(*calls.lock().unwrap(), 2);
+    }
+}
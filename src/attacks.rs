@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use serde::Serialize;
+use schemars::JsonSchema;
+use crate::spatial::NearbyMode;
+use crate::World;
+
+
+const ATTACK_SAMPLE_INTERVAL: i64 = 1000;
+// window for recent average power before the surge
+const ATTACK_AVERAGE_WINDOW: i64 = 30_000;
+// riders closer than this at surge start are attacker's group
+const ATTACK_GROUP_RADIUS: f64 = 25.;
+const ATTACK_SEARCH_RADIUS: f64 = 500.;
+const ATTACK_EVENTS_CAPACITY: usize = 100;
+
+#[derive(Debug,Clone)]
+pub struct AttackConfig {
+    /// power above recent average multiplied by ratio
+    pub power_ratio: f64,
+    pub min_power: f64,
+    /// time after surge to open the gap, ms
+    pub window: i64,
+    pub min_gap: f64
+}
+
+impl Default for AttackConfig {
+    fn default() -> Self {
+        AttackConfig {
+            power_ratio: 1.5,
+            min_power: 300.,
+            window: 10_000,
+            min_gap: 10.
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct AttackEvent {
+    pub id: u64,
+    pub attacker_id: i32,
+    /// world time of surge start
+    pub world_time: i64,
+    pub detected_at: i64,
+    /// distance to the nearest rider of attacker's group
+    pub gap: f64,
+    pub power: f64,
+    pub average_power: f64
+}
+
+#[derive(Debug,Clone)]
+struct Surge {
+    start_time: i64,
+    power: f64,
+    average_power: f64,
+    group: Vec<i32>
+}
+
+
+#[derive(Debug,Default)]
+pub struct AttackDetector {
+    pub config: AttackConfig,
+    last_sample: i64,
+    power_samples: HashMap<i32, VecDeque<(i64, f64)>>,
+    surges: HashMap<i32, Surge>,
+    // riders still above surge threshold, new surge only after power drops
+    surging: HashSet<i32>,
+    events: VecDeque<AttackEvent>,
+    next_event_id: u64
+}
+
+impl AttackDetector {
+    pub fn new(config: AttackConfig) -> Self {
+        AttackDetector {
+            config,
+            ..AttackDetector::default()
+        }
+    }
+
+    fn average_power(&mut self, player_id: i32, world_time: i64, power: f64) -> Option<f64> {
+        let samples = self.power_samples.entry(player_id).or_insert_with(VecDeque::new);
+        while let Some(&(time, _)) = samples.front() {
+            if world_time - time > ATTACK_AVERAGE_WINDOW {
+                samples.pop_front();
+            } else {
+                break;
+            }
+        }
+        let average = match samples.is_empty() {
+            true => None,
+            false => Some(samples.iter().map(|&(_, power)| power).sum::<f64>() / samples.len() as f64)
+        };
+        samples.push_back((world_time, power));
+        average
+    }
+
+    fn push_event(&mut self, attacker_id: i32, surge: &Surge, detected_at: i64, gap: f64) {
+        if self.events.len() >= ATTACK_EVENTS_CAPACITY {
+            self.events.pop_front();
+        }
+        self.next_event_id += 1;
+        self.events.push_back(AttackEvent {
+            id: self.next_event_id,
+            attacker_id,
+            world_time: surge.start_time,
+            detected_at,
+            gap,
+            power: surge.power,
+            average_power: surge.average_power
+        });
+    }
+
+    pub fn update(&mut self, world: &World) {
+        if world.world_time - self.last_sample < ATTACK_SAMPLE_INTERVAL {
+            return;
+        }
+        self.last_sample = world.world_time;
+        // riders removed from watch group or world
+        let group = &world.group_to_watch;
+        self.power_samples.retain(|&id, _| group.has_player(id));
+        self.surges.retain(|&id, _| group.has_player(id));
+        self.surging.retain(|&id| group.has_player(id));
+
+        for player_id in world.group_to_watch.iter() {
+            let player = match world.get_player_data(player_id).and_then(|player| player.get_latest()) {
+                Some(player) => player,
+                _ => continue
+            };
+            let power = player.power as f64;
+            let average = self.average_power(player_id, player.world_time, power);
+
+            if let Some(surge) = self.surges.get(&player_id).cloned() {
+                if player.world_time - surge.start_time > self.config.window {
+                    self.surges.remove(&player_id);
+                    continue;
+                }
+                let nearby = world.find_nearby_players(player_id, ATTACK_SEARCH_RADIUS, NearbyMode::Road).unwrap_or_default();
+                // riders of the group out of search radius are far behind
+                let gap = surge.group.iter()
+                    .filter_map(|id| nearby.iter().find(|other| other.id == *id))
+                    .map(|other| -other.gap)
+                    .fold(ATTACK_SEARCH_RADIUS, f64::min);
+                if gap >= self.config.min_gap {
+                    self.surges.remove(&player_id);
+                    self.push_event(player_id, &surge, player.world_time, gap);
+                }
+                continue;
+            }
+
+            let average = match average {
+                Some(average) if average > 0. => average,
+                _ => continue
+            };
+            if power < self.config.min_power || power < average * self.config.power_ratio {
+                self.surging.remove(&player_id);
+                continue;
+            }
+            if !self.surging.insert(player_id) {
+                continue;
+            }
+            let group: Vec<i32> = world.find_nearby_players(player_id, ATTACK_GROUP_RADIUS, NearbyMode::Euclidean)
+                .unwrap_or_default()
+                .iter()
+                .map(|other| other.id)
+                .collect();
+            if group.is_empty() {
+                continue;
+            }
+            self.surges.insert(player_id, Surge {
+                start_time: player.world_time,
+                power,
+                average_power: average,
+                group
+            });
+        }
+    }
+
+    /// Events with id greater than given one, oldest first
+    pub fn events_since(&self, event_id: u64) -> Vec<AttackEvent> {
+        self.events.iter().filter(|event| event.id > event_id).cloned().collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn attack_detector_update() {
+        let mut world = World::new();
+        world.attacks.config.window = 5000;
+        let base_player = get_player_instance();
+        // riders together for 10 sec, then rider 1 attacks and rides away
+        for step in 0..16 {
+            let attack = step >= 10;
+            for id in 1..4 {
+                let mut player = base_player.clone();
+                player.id = id;
+                player.world_time = base_player.world_time + step * 1000;
+                player.x = step as f64 * 10. + id as f64;
+                if attack && id == 1 {
+                    player.x += (step - 9) as f64 * 4.;
+                }
+                player.y = 0.;
+                player.power = match attack && id == 1 {
+                    true => 600,
+                    false => 250
+                } as _;
+                world.push_player(player);
+            }
+            if step == 0 {
+                world.add_player_to_watch(1);
+                world.add_player_to_watch(2);
+            }
+            world.push_players_batch(Vec::new());
+        }
+        let events = world.attacks.events_since(0);
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.attacker_id, 1);
+        assert_eq!(event.world_time, base_player.world_time + 10_000);
+        assert!(event.gap >= world.attacks.config.min_gap);
+        assert_eq!(event.power, 600.);
+        assert_eq!(event.average_power, 250.);
+        assert!(world.attacks.events_since(event.id).is_empty());
+
+        assert!(world.attacks.power_samples.contains_key(&2));
+        world.remove_player_from_watch(2);
+        let mut player = world.get_player_data(1).unwrap().get_latest().unwrap();
+        player.world_time += ATTACK_SAMPLE_INTERVAL;
+        world.push_player(player);
+        world.push_players_batch(Vec::new());
+        assert!(!world.attacks.power_samples.contains_key(&2));
+        assert!(world.attacks.power_samples.contains_key(&1));
+    }
+}
@@ -0,0 +1,390 @@
+use pcap::Linktype;
+use zwift_capture::ZwiftMessage;
+use crate::profiles::PlayerProfile;
+
+
+/// Zwift game server UDP port
+pub const ZWIFT_UDP_PORT: u16 = 3022;
+
+// link layer types returned by `pcap::Capture::get_datalink`
+const DLT_NULL: i32 = 0;
+const DLT_EN10MB: i32 = 1;
+const DLT_PPP: i32 = 9;
+const DLT_RAW: i32 = 12;
+const DLT_RAW_OPENBSD: i32 = 14;
+const LINKTYPE_RAW: i32 = 101;
+const DLT_LOOP: i32 = 108;
+const DLT_LINUX_SLL: i32 = 113;
+const DLT_IPV4: i32 = 228;
+const DLT_LINUX_SLL2: i32 = 276;
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const VLAN_ETHERNET_HEADER_LEN: usize = 18;
+const LOOPBACK_HEADER_LEN: usize = 4;
+const LINUX_SLL_HEADER_LEN: usize = 16;
+const LINUX_SLL2_HEADER_LEN: usize = 20;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const AF_INET: u32 = 2;
+const PPP_ADDRESS_CONTROL: u16 = 0xff03;
+const PPP_IPV4: u16 = 0x0021;
+const IP_PROTOCOL_UDP: u8 = 17;
+const UDP_HEADER_LEN: usize = 8;
+
+// protobuf field numbers of server to client messages
+const SERVER_TO_CLIENT_PLAYER_STATES: u64 = 8;
+const SERVER_TO_CLIENT_UPDATES: u64 = 9;
+const PLAYER_STATE_ID: u64 = 1;
+// flags with road id in second byte
+const PLAYER_STATE_ROAD_FLAGS: u64 = 20;
+const WORLD_ATTRIBUTE_TYPE: u64 = 2;
+const WORLD_ATTRIBUTE_PAYLOAD: u64 = 3;
+const WORLD_ATTRIBUTE_RIDE_ON: u64 = 4;
+const WORLD_ATTRIBUTE_CHAT: u64 = 5;
+const WORLD_ATTRIBUTE_JOINED_EVENT: u64 = 7;
+const WORLD_ATTRIBUTE_LEFT_EVENT: u64 = 8;
+const RIDE_ON_PLAYER_ID: u64 = 1;
+const RIDE_ON_FIRST_NAME: u64 = 3;
+const RIDE_ON_LAST_NAME: u64 = 4;
+const CHAT_PLAYER_ID: u64 = 1;
+const CHAT_FIRST_NAME: u64 = 4;
+const CHAT_LAST_NAME: u64 = 5;
+const EVENT_MEMBERSHIP_EVENT_ID: u64 = 1;
+const EVENT_MEMBERSHIP_PLAYER_ID: u64 = 2;
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_BYTES: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+
+/// Offset of IPv4 packet in captured frame, `None` for other protocols
+fn ipv4_offset(linktype: Linktype, frame: &[u8]) -> Option<usize> {
+    let be16 = |offset: usize| Some(u16::from_be_bytes([*frame.get(offset)?, *frame.get(offset + 1)?]));
+    let family = |big_endian: bool| {
+        let bytes = [*frame.first()?, *frame.get(1)?, *frame.get(2)?, *frame.get(3)?];
+        Some(match big_endian {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes)
+        })
+    };
+    match linktype.0 {
+        DLT_EN10MB => match be16(12)? {
+            ETHERTYPE_IPV4 => Some(ETHERNET_HEADER_LEN),
+            ETHERTYPE_VLAN if be16(16)? == ETHERTYPE_IPV4 => Some(VLAN_ETHERNET_HEADER_LEN),
+            _ => None
+        },
+        // address family in byte order of capturing host
+        DLT_NULL if family(false)? == AF_INET || family(true)? == AF_INET => Some(LOOPBACK_HEADER_LEN),
+        DLT_LOOP if family(true)? == AF_INET => Some(LOOPBACK_HEADER_LEN),
+        DLT_PPP => match be16(0)? {
+            PPP_ADDRESS_CONTROL if be16(2)? == PPP_IPV4 => Some(4),
+            PPP_IPV4 => Some(2),
+            _ => None
+        },
+        DLT_LINUX_SLL if be16(14)? == ETHERTYPE_IPV4 => Some(LINUX_SLL_HEADER_LEN),
+        DLT_LINUX_SLL2 if be16(0)? == ETHERTYPE_IPV4 => Some(LINUX_SLL2_HEADER_LEN),
+        DLT_RAW | DLT_RAW_OPENBSD | LINKTYPE_RAW | DLT_IPV4 => Some(0),
+        _ => None
+    }
+}
+
+/// Payload of UDP datagram over IPv4 in captured frame of given link type
+/// (Ethernet, loopback, PPP, Linux "any" device or raw IP)
+pub fn udp_payload(linktype: Linktype, frame: &[u8]) -> Option<&[u8]> {
+    let ip = frame.get(ipv4_offset(linktype, frame)?..)?;
+    if *ip.first()? >> 4 != 4 || *ip.get(9)? != IP_PROTOCOL_UDP {
+        return None;
+    }
+    let ip_header_len = (*ip.first()? & 0x0f) as usize * 4;
+    let udp = ip.get(ip_header_len..)?;
+    // Ethernet frames are padded to minimal length
+    let udp_len = u16::from_be_bytes([*udp.get(4)?, *udp.get(5)?]) as usize;
+    udp.get(UDP_HEADER_LEN..udp_len.min(udp.len()).max(UDP_HEADER_LEN))
+}
+
+
+#[derive(Debug,Clone,PartialEq)]
+enum WireValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8])
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*position)?;
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Top level fields of protobuf message, `None` if message is malformed
+fn read_fields(data: &[u8]) -> Option<Vec<(u64, WireValue<'_>)>> {
+    let mut fields = Vec::new();
+    let mut position = 0;
+    while position < data.len() {
+        let key = read_varint(data, &mut position)?;
+        let value = match key & 0x07 {
+            WIRE_VARINT => WireValue::Varint(read_varint(data, &mut position)?),
+            WIRE_BYTES => {
+                let len = read_varint(data, &mut position)? as usize;
+                let value = data.get(position..position.checked_add(len)?)?;
+                position += len;
+                WireValue::Bytes(value)
+            },
+            WIRE_FIXED64 => {
+                position += 8;
+                continue;
+            },
+            WIRE_FIXED32 => {
+                position += 4;
+                continue;
+            },
+            _ => return None
+        };
+        fields.push((key >> 3, value));
+    }
+    match position == data.len() {
+        true => Some(fields),
+        false => None
+    }
+}
+
+fn varint_field(fields: &[(u64, WireValue)], number: u64) -> Option<u64> {
+    fields.iter().find_map(|(field, value)| match value {
+        WireValue::Varint(value) if *field == number => Some(*value),
+        _ => None
+    })
+}
+
+fn string_field(fields: &[(u64, WireValue)], number: u64) -> Option<String> {
+    fields.iter().find_map(|(field, value)| match value {
+        WireValue::Bytes(value) if *field == number => std::str::from_utf8(value).ok().map(String::from),
+        _ => None
+    })
+}
+
+fn profile_from_names(fields: &[(u64, WireValue)], player_id: u64, first_name: u64, last_name: u64) -> Option<(i32, PlayerProfile)> {
+    let player_id = varint_field(fields, player_id)? as i32;
+    let name = [string_field(fields, first_name), string_field(fields, last_name)].iter()
+        .flatten()
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ");
+    if player_id <= 0 || name.is_empty() {
+        return None;
+    }
+    Some((player_id, PlayerProfile { name: Some(name), ..PlayerProfile::default() }))
+}
+
+/// Data of server message which isn't exposed by `zwift_capture::Player`
+#[derive(Debug,Clone,Default,PartialEq)]
+pub struct ServerUpdate {
+    /// rider names from ride ons and chat messages
+    pub profiles: Vec<(i32, PlayerProfile)>,
+    /// road id of every player state
+    pub road_ids: Vec<(i32, i32)>,
+    /// (player id, event id) of riders joined event, event id is `None` when rider left event
+    pub events: Vec<(i32, Option<i64>)>
+}
+
+fn decode_world_attribute(attribute: &[u8], update: &mut ServerUpdate) {
+    let attribute = match read_fields(attribute) {
+        Some(attribute) => attribute,
+        _ => return
+    };
+    let payload = match attribute.iter().find(|(field, _)| *field == WORLD_ATTRIBUTE_PAYLOAD) {
+        Some((_, WireValue::Bytes(payload))) => read_fields(payload).unwrap_or_default(),
+        _ => return
+    };
+    let event_membership = |joined: bool| {
+        let player_id = varint_field(&payload, EVENT_MEMBERSHIP_PLAYER_ID)? as i32;
+        let event_id = varint_field(&payload, EVENT_MEMBERSHIP_EVENT_ID)? as i64;
+        Some((player_id, match joined {
+            true => Some(event_id),
+            false => None
+        }))
+    };
+    match varint_field(&attribute, WORLD_ATTRIBUTE_TYPE) {
+        Some(WORLD_ATTRIBUTE_RIDE_ON) => update.profiles.extend(profile_from_names(&payload, RIDE_ON_PLAYER_ID, RIDE_ON_FIRST_NAME, RIDE_ON_LAST_NAME)),
+        Some(WORLD_ATTRIBUTE_CHAT) => update.profiles.extend(profile_from_names(&payload, CHAT_PLAYER_ID, CHAT_FIRST_NAME, CHAT_LAST_NAME)),
+        Some(WORLD_ATTRIBUTE_JOINED_EVENT) => update.events.extend(event_membership(true)),
+        Some(WORLD_ATTRIBUTE_LEFT_EVENT) => update.events.extend(event_membership(false)),
+        _ => {}
+    }
+}
+
+/// Rider names, roads and event membership from message broadcast by server.
+/// Player states are decoded by `zwift_capture`, messages it rejects are skipped
+pub fn decode_server_message(message: &ZwiftMessage) -> ServerUpdate {
+    let mut update = ServerUpdate::default();
+    let payload = match message {
+        ZwiftMessage::FromServer(payload) => payload,
+        _ => return update
+    };
+    if message.get_players().is_err() {
+        return update;
+    }
+    let fields = match read_fields(payload) {
+        Some(fields) => fields,
+        _ => return update
+    };
+    for (field, value) in fields.iter() {
+        match (*field, value) {
+            (SERVER_TO_CLIENT_PLAYER_STATES, WireValue::Bytes(state)) => {
+                let state = read_fields(state).unwrap_or_default();
+                if let (Some(player_id), Some(flags)) = (varint_field(&state, PLAYER_STATE_ID), varint_field(&state, PLAYER_STATE_ROAD_FLAGS)) {
+                    update.road_ids.push((player_id as i32, ((flags >> 8) & 0xff) as i32));
+                }
+            },
+            (SERVER_TO_CLIENT_UPDATES, WireValue::Bytes(attribute)) => decode_world_attribute(attribute, &mut update),
+            _ => {}
+        }
+    }
+    update
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::World;
+
+    fn encode_varint(mut value: u64, output: &mut Vec<u8>) {
+        while value >= 0x80 {
+            output.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        output.push(value as u8);
+    }
+
+    fn encode_varint_field(field: u64, value: u64, output: &mut Vec<u8>) {
+        encode_varint(field << 3 | WIRE_VARINT, output);
+        encode_varint(value, output);
+    }
+
+    fn encode_bytes_field(field: u64, value: &[u8], output: &mut Vec<u8>) {
+        encode_varint(field << 3 | WIRE_BYTES, output);
+        encode_varint(value.len() as u64, output);
+        output.extend_from_slice(value);
+    }
+
+    /// Server message with single world attribute
+    fn world_attribute_message(attribute_type: u64, payload: &[u8]) -> Vec<u8> {
+        let mut attribute = Vec::new();
+        encode_varint_field(1, 42, &mut attribute);
+        encode_varint_field(WORLD_ATTRIBUTE_TYPE, attribute_type, &mut attribute);
+        encode_bytes_field(WORLD_ATTRIBUTE_PAYLOAD, payload, &mut attribute);
+        let mut message = Vec::new();
+        encode_varint_field(3, 199877475562, &mut message);
+        message.extend_from_slice(&[0x2d, 0, 0, 0, 0]); // fixed32 field 5
+        encode_bytes_field(SERVER_TO_CLIENT_UPDATES, &attribute, &mut message);
+        message
+    }
+
+    /// Server message with ride on from given rider
+    fn ride_on_message(player_id: i32, first_name: &str, last_name: &str) -> Vec<u8> {
+        let mut ride_on = Vec::new();
+        encode_varint_field(RIDE_ON_PLAYER_ID, player_id as u64, &mut ride_on);
+        encode_varint_field(2, 108934, &mut ride_on);
+        encode_bytes_field(RIDE_ON_FIRST_NAME, first_name.as_bytes(), &mut ride_on);
+        encode_bytes_field(RIDE_ON_LAST_NAME, last_name.as_bytes(), &mut ride_on);
+        world_attribute_message(WORLD_ATTRIBUTE_RIDE_ON, &ride_on)
+    }
+
+    fn event_message(attribute_type: u64, player_id: i32, event_id: i64) -> Vec<u8> {
+        let mut membership = Vec::new();
+        encode_varint_field(EVENT_MEMBERSHIP_EVENT_ID, event_id as u64, &mut membership);
+        encode_varint_field(EVENT_MEMBERSHIP_PLAYER_ID, player_id as u64, &mut membership);
+        world_attribute_message(attribute_type, &membership)
+    }
+
+    #[test]
+    fn decoder_road_ids() {
+        let mut state = Vec::new();
+        encode_varint_field(PLAYER_STATE_ID, 108934, &mut state);
+        encode_varint_field(PLAYER_STATE_ROAD_FLAGS, 0x1f_05_12, &mut state);
+        let mut message = Vec::new();
+        encode_bytes_field(SERVER_TO_CLIENT_PLAYER_STATES, &state, &mut message);
+        message.extend(ride_on_message(5, "Rider", "One"));
+        let update = decode_server_message(&ZwiftMessage::FromServer(&message));
+        assert_eq!(update.road_ids, vec![(108934, 5)]);
+        assert_eq!(update.profiles.len(), 1);
+        assert_eq!(decode_server_message(&ZwiftMessage::ToServer(&message)), ServerUpdate::default());
+    }
+
+    fn ipv4_udp(payload: &[u8]) -> Vec<u8> {
+        let mut ip = vec![0u8; 20];
+        ip[0] = 0x45;
+        ip[9] = IP_PROTOCOL_UDP;
+        ip.extend_from_slice(&[0x0b, 0xce, 0xc3, 0x50, 0, (UDP_HEADER_LEN + payload.len()) as u8, 0, 0]);
+        ip.extend_from_slice(payload);
+        ip
+    }
+
+    #[test]
+    fn decoder_udp_payload() {
+        let mut frame = vec![0u8; 12];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend(ipv4_udp(b"abc"));
+        // Ethernet padding
+        frame.extend_from_slice(&[0, 0]);
+        assert_eq!(udp_payload(Linktype(DLT_EN10MB), &frame), Some(&b"abc"[..]));
+        assert_eq!(udp_payload(Linktype(DLT_LINUX_SLL), &frame), None);
+        frame[14 + 9] = 6;
+        assert_eq!(udp_payload(Linktype(DLT_EN10MB), &frame), None);
+        assert_eq!(udp_payload(Linktype(DLT_EN10MB), &frame[..10]), None);
+
+        // loopback device, address family in host byte order
+        let mut frame = vec![2, 0, 0, 0];
+        frame.extend(ipv4_udp(b"abc"));
+        assert_eq!(udp_payload(Linktype(DLT_NULL), &frame), Some(&b"abc"[..]));
+        assert_eq!(udp_payload(Linktype(DLT_LOOP), &frame), None);
+
+        // Linux "any" device
+        let mut frame = vec![0u8; 14];
+        frame.extend_from_slice(&[0x08, 0x00]);
+        frame.extend(ipv4_udp(b"abc"));
+        assert_eq!(udp_payload(Linktype(DLT_LINUX_SLL), &frame), Some(&b"abc"[..]));
+
+        let mut frame = vec![0xff, 0x03, 0x00, 0x21];
+        frame.extend(ipv4_udp(b"abc"));
+        assert_eq!(udp_payload(Linktype(DLT_PPP), &frame), Some(&b"abc"[..]));
+        assert_eq!(udp_payload(Linktype(DLT_RAW), &ipv4_udp(b"abc")), Some(&b"abc"[..]));
+        assert_eq!(udp_payload(Linktype(147), &ipv4_udp(b"abc")), None);
+    }
+
+    #[test]
+    fn decoder_profiles() {
+        let payload = ride_on_message(5, "Rider", "One");
+        let profiles = decode_server_message(&ZwiftMessage::FromServer(&payload)).profiles;
+        assert_eq!(profiles, vec![(5, PlayerProfile { name: Some(String::from("Rider One")), ..PlayerProfile::default() })]);
+        assert!(decode_server_message(&ZwiftMessage::FromServer(&payload[..payload.len() - 2])).profiles.is_empty());
+        assert!(decode_server_message(&ZwiftMessage::FromServer(&ride_on_message(6, " ", ""))).profiles.is_empty());
+
+        let mut world = World::new();
+        assert_eq!(world.push_profiles_batch(profiles), 1);
+        assert_eq!(world.profiles.name(5), Some("Rider One"));
+    }
+
+    #[test]
+    fn decoder_events() {
+        let joined = decode_server_message(&ZwiftMessage::FromServer(&event_message(WORLD_ATTRIBUTE_JOINED_EVENT, 5, 2_563_214)));
+        assert_eq!(joined.events, vec![(5, Some(2_563_214))]);
+        let left = decode_server_message(&ZwiftMessage::FromServer(&event_message(WORLD_ATTRIBUTE_LEFT_EVENT, 5, 2_563_214)));
+        assert_eq!(left.events, vec![(5, None)]);
+
+        let mut world = World::new();
+        world.push_events(joined.events);
+        assert_eq!(world.get_event_id(5), Some(2_563_214));
+        world.push_events(left.events);
+        assert_eq!(world.get_event_id(5), None);
+    }
+}
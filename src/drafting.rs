@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use serde::Serialize;
+use schemars::JsonSchema;
+use crate::spatial::NearbyMode;
+use crate::World;
+
+
+const DRAFT_SAMPLE_INTERVAL: i64 = 1000;
+// longer pauses between samples (no data) are not counted
+const DRAFT_MAX_SAMPLE_TIME: i64 = 3 * DRAFT_SAMPLE_INTERVAL;
+// band behind another rider, along direction of travel
+const DRAFT_MIN_GAP: f64 = 0.5;
+const DRAFT_MAX_GAP: f64 = 5.;
+// nobody ahead within this gap to be on the front
+const FRONT_GAP: f64 = 10.;
+const DRAFT_MIN_SPEED: f64 = 1.;
+// cosine of largest angle between directions of travel, oncoming riders don't shelter
+const DRAFT_MIN_DIRECTION_COS: f64 = 0.9;
+
+#[derive(Debug, Serialize, JsonSchema, Clone, Default, PartialEq)]
+pub struct DraftStats {
+    pub sampled_time: i64,
+    pub draft_time: i64,
+    pub front_time: i64,
+    pub draft_percent: f64,
+    pub front_percent: f64,
+    /// rider sheltering behind at latest sample
+    pub drafting: bool,
+    pub drafting_behind: Option<i32>,
+    pub on_front: bool
+}
+
+impl DraftStats {
+    fn add_sample(&mut self, duration: i64, drafting_behind: Option<i32>, on_front: bool) {
+        self.sampled_time += duration;
+        self.drafting = drafting_behind.is_some();
+        self.drafting_behind = drafting_behind;
+        self.on_front = on_front;
+        if self.drafting {
+            self.draft_time += duration;
+        }
+        if on_front {
+            self.front_time += duration;
+        }
+        if self.sampled_time > 0 {
+            self.draft_percent = self.draft_time as f64 * 100. / self.sampled_time as f64;
+            self.front_percent = self.front_time as f64 * 100. / self.sampled_time as f64;
+        }
+    }
+}
+
+
+/// Draft and "time on the front" accumulated for watched riders
+#[derive(Debug,Default)]
+pub struct DraftTracker {
+    last_sample: Option<i64>,
+    stats: HashMap<i32, DraftStats>
+}
+
+impl DraftTracker {
+    pub fn new() -> Self {
+        DraftTracker::default()
+    }
+
+    pub fn update(&mut self, world: &World) {
+        let last_sample = match self.last_sample {
+            Some(last_sample) if world.world_time - last_sample < DRAFT_SAMPLE_INTERVAL => return,
+            Some(last_sample) => last_sample,
+            // nothing to credit before first sample
+            _ => {
+                self.last_sample = Some(world.world_time);
+                return;
+            }
+        };
+        let duration = (world.world_time - last_sample).min(DRAFT_MAX_SAMPLE_TIME);
+        self.last_sample = Some(world.world_time);
+
+        for player_id in world.group_to_watch.iter() {
+            let player_data = match world.get_player_data(player_id) {
+                Some(player_data) => player_data,
+                _ => continue
+            };
+            let moving = player_data.get_latest()
+                .map(|player| player.speed as f64 > DRAFT_MIN_SPEED)
+                .unwrap_or(false);
+            // gaps along the road make sense only with known direction of travel,
+            // it's taken from position change as `heading` isn't interpolated
+            let direction = match player_data.get_direction(player_data.world_time) {
+                Some(direction) if moving => direction,
+                _ => continue
+            };
+            let nearby = match world.find_nearby_players(player_id, FRONT_GAP, NearbyMode::Road) {
+                Some(nearby) => nearby,
+                _ => continue
+            };
+            let nearby: Vec<_> = nearby.into_iter()
+                .filter(|other| {
+                    world.get_player_data(other.id)
+                        .and_then(|other| other.get_direction(other.world_time))
+                        .map(|other| other.0 * direction.0 + other.1 * direction.1 >= DRAFT_MIN_DIRECTION_COS)
+                        .unwrap_or(false)
+                })
+                .collect();
+            let drafting_behind = nearby.iter()
+                .filter(|other| other.gap >= DRAFT_MIN_GAP && other.gap <= DRAFT_MAX_GAP)
+                .min_by(|a, b| a.gap.partial_cmp(&b.gap).unwrap_or(std::cmp::Ordering::Equal))
+                .map(|other| other.id);
+            let nobody_ahead = nearby.iter().all(|other| other.gap <= 0.);
+            let sheltering_rider = nearby.iter().any(|other| other.gap <= -DRAFT_MIN_GAP && other.gap >= -DRAFT_MAX_GAP);
+            self.stats.entry(player_id)
+                .or_insert_with(DraftStats::default)
+                .add_sample(duration, drafting_behind, nobody_ahead && sheltering_rider);
+        }
+    }
+
+    pub fn get(&self, player_id: i32) -> Option<&DraftStats> {
+        self.stats.get(&player_id)
+    }
+
+    pub fn reset(&mut self) {
+        self.stats.clear();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn draft_tracker_update() {
+        let mut world = World::new();
+        let base_player = get_player_instance();
+        // rider 1 behind rider 2, rider 3 alone far behind, rider 4 coming towards rider 3
+        for step in 0..6 {
+            for (id, x, speed) in [(1, 0., 5.), (2, 3., 5.), (3, -500., 5.), (4, -473., -1.)].iter() {
+                let mut player = base_player.clone();
+                player.id = *id;
+                player.world_time = base_player.world_time + step * 500;
+                player.x = x + step as f64 * speed;
+                player.y = 0.;
+                player.speed = 10. as _;
+                world.push_player(player);
+            }
+            if step == 0 {
+                world.add_player_to_watch(1);
+                world.add_player_to_watch(2);
+                world.add_player_to_watch(3);
+            }
+            world.push_players_batch(Vec::new());
+        }
+        let first = world.drafting.get(1).unwrap();
+        assert!(first.drafting);
+        assert_eq!(first.drafting_behind, Some(2));
+        assert_eq!(first.draft_time, first.sampled_time);
+        assert_eq!(first.draft_percent, 100.);
+        let second = world.drafting.get(2).unwrap();
+        assert!(!second.drafting);
+        assert!(second.on_front);
+        assert_eq!(second.front_percent, 100.);
+        let alone = world.drafting.get(3).unwrap();
+        assert!(!alone.drafting && !alone.on_front);
+        assert_eq!(alone.draft_time, 0);
+        // first sample isn't credited
+        assert_eq!(alone.sampled_time, 2000);
+    }
+}
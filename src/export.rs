@@ -0,0 +1,229 @@
+use std::io::{self, Write};
+use std::str::FromStr;
+use chrono::{TimeZone, Utc};
+use serde_json::{Map, Value};
+use zwift_capture::Player;
+use crate::ZWIFT_EPOCH;
+
+
+// world time as UTC date, `ZwiftCapture` doesn't expose packet timestamps
+const WORLD_DATETIME_FIELD: &str = "world_datetime";
+
+/// UTC date of world time, empty when out of range
+pub fn format_world_time(world_time: i64) -> String {
+    match world_time.checked_add(ZWIFT_EPOCH).map(|time| Utc.timestamp_millis_opt(time).single()) {
+        Some(Some(datetime)) => datetime.format("%Y-%m-%d %H:%M:%S.%f").to_string(),
+        _ => String::new()
+    }
+}
+
+pub fn escape_csv(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        String::from(value)
+    }
+}
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum ExportFormat {
+    Csv,
+    JsonLines
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" | "json-lines" | "jsonlines" => Ok(ExportFormat::JsonLines),
+            _ => Err(format!("Unknown export format: {}", value))
+        }
+    }
+}
+
+
+#[derive(Debug,Clone,Default)]
+pub struct ExportFilter {
+    pub player_ids: Vec<i32>,
+    pub group_ids: Vec<i32>,
+    pub from_time: Option<i64>,
+    pub to_time: Option<i64>
+}
+
+impl ExportFilter {
+    pub fn new() -> Self {
+        ExportFilter::default()
+    }
+
+    pub fn matches(&self, player: &Player) -> bool {
+        if !self.player_ids.is_empty() && !self.player_ids.contains(&player.id) {
+            return false;
+        }
+        if !self.group_ids.is_empty() && !self.group_ids.contains(&player.group_id) {
+            return false;
+        }
+        if let Some(from_time) = self.from_time {
+            if player.world_time < from_time {
+                return false;
+            }
+        }
+        if let Some(to_time) = self.to_time {
+            if player.world_time > to_time {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+
+pub struct Exporter<W: Write> {
+    writer: W,
+    format: ExportFormat,
+    filter: ExportFilter,
+    // csv columns, taken from the first written record
+    header: Option<Vec<String>>,
+    count: usize
+}
+
+impl<W: Write> Exporter<W> {
+    pub fn new(writer: W, format: ExportFormat, filter: ExportFilter) -> Self {
+        Exporter {
+            writer,
+            format,
+            filter,
+            header: None,
+            count: 0
+        }
+    }
+
+    fn to_record(player: &Player) -> Map<String, Value> {
+        let mut record = match serde_json::to_value(player) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new()
+        };
+        record.insert(String::from(WORLD_DATETIME_FIELD), Value::String(format_world_time(player.world_time)));
+        record
+    }
+
+    fn csv_value(value: Option<&Value>) -> String {
+        match value {
+            Some(Value::String(v)) => escape_csv(v),
+            Some(Value::Null) | None => String::new(),
+            Some(v) => escape_csv(&v.to_string())
+        }
+    }
+
+    fn write_csv(&mut self, record: &Map<String, Value>) -> io::Result<()> {
+        if self.header.is_none() {
+            let mut header = vec![String::from(WORLD_DATETIME_FIELD)];
+            header.extend(record.keys().filter(|&key| key != WORLD_DATETIME_FIELD).cloned());
+            writeln!(self.writer, "{}", header.join(","))?;
+            self.header = Some(header);
+        }
+        if let Some(header) = &self.header {
+            let row: Vec<String> = header.iter()
+                .map(|key| Exporter::<W>::csv_value(record.get(key)))
+                .collect();
+            writeln!(self.writer, "{}", row.join(","))?;
+        }
+        Ok(())
+    }
+
+    fn write_json_line(&mut self, record: Map<String, Value>) -> io::Result<()> {
+        let line = serde_json::to_string(&Value::Object(record))?;
+        writeln!(self.writer, "{}", line)
+    }
+
+    pub fn write_player(&mut self, player: &Player) -> io::Result<bool> {
+        if !self.filter.matches(player) {
+            return Ok(false);
+        }
+        let record = Exporter::<W>::to_record(player);
+        match self.format {
+            ExportFormat::Csv => self.write_csv(&record)?,
+            ExportFormat::JsonLines => self.write_json_line(record)?
+        }
+        self.count += 1;
+        Ok(true)
+    }
+
+    pub fn write_batch(&mut self, players: &[Player]) -> io::Result<usize> {
+        let mut written = 0;
+        for player in players.iter() {
+            if self.write_player(player)? {
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn export_filter_matches() {
+        let player = get_player_instance();
+        let mut filter = ExportFilter::new();
+        assert!(filter.matches(&player));
+        filter.player_ids.push(player.id + 1);
+        assert!(!filter.matches(&player));
+        filter.player_ids.push(player.id);
+        filter.from_time = Some(player.world_time + 1);
+        assert!(!filter.matches(&player));
+        filter.from_time = Some(player.world_time);
+        filter.to_time = Some(player.world_time);
+        assert!(filter.matches(&player));
+    }
+
+    #[test]
+    fn export_format_world_time() {
+        assert_eq!(format_world_time(0), "2014-10-22 22:14:35.000000000");
+        assert_eq!(format_world_time(i64::MAX), "");
+        assert_eq!(format_world_time(i64::MIN), "");
+    }
+
+    #[test]
+    fn export_csv() {
+        let player = get_player_instance();
+        let mut exporter = Exporter::new(Vec::new(), ExportFormat::Csv, ExportFilter::new());
+        assert_eq!(exporter.write_batch(&[player.clone(), player]).unwrap(), 2);
+        let output = String::from_utf8(exporter.finish().unwrap()).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("world_datetime,"));
+        assert!(lines[0].split(',').any(|column| column == "world_time"));
+        assert_eq!(lines[1], lines[2]);
+    }
+
+    #[test]
+    fn export_json_lines() {
+        let player = get_player_instance();
+        let mut filter = ExportFilter::new();
+        filter.group_ids.push(player.group_id);
+        let mut exporter = Exporter::new(Vec::new(), ExportFormat::JsonLines, filter);
+        exporter.write_player(&player).unwrap();
+        assert_eq!(exporter.count(), 1);
+        let output = String::from_utf8(exporter.finish().unwrap()).unwrap();
+        let record: Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(record["id"], player.id);
+        assert_eq!(record["world_time"], player.world_time);
+        assert!(record[WORLD_DATETIME_FIELD].is_string());
+    }
+}
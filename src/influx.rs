@@ -0,0 +1,254 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use serde_json::Value;
+use tokio::sync::mpsc::Receiver;
+use zwift_capture::Player;
+use crate::ZWIFT_EPOCH;
+
+
+const INFLUX_MEASUREMENT: &str = "zwift_player";
+// fields which goes to tags or timestamp
+const INFLUX_SKIP_FIELDS: [&str; 3] = ["id", "group_id", "world_time"];
+/// Player batches waiting in channel from capture thread, newer batches are dropped when full
+pub const INFLUX_CHANNEL_CAPACITY: usize = 1000;
+
+#[derive(Debug,Clone)]
+pub struct InfluxConfig {
+    /// full write url, e.g. http://localhost:8086/api/v2/write?org=team&bucket=zwift&precision=ms
+    pub url: String,
+    pub token: Option<String>,
+    pub event: Option<String>,
+    pub batch_size: usize,
+    pub max_queue: usize,
+    pub flush_interval: Duration,
+    /// single write request timeout
+    pub timeout: Duration
+}
+
+impl InfluxConfig {
+    pub fn new(url: &str) -> Self {
+        InfluxConfig {
+            url: String::from(url),
+            token: None,
+            event: None,
+            batch_size: 5000,
+            max_queue: 100_000,
+            flush_interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5)
+        }
+    }
+}
+
+fn escape_tag(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+/// Player update as InfluxDB line, unix timestamp in milliseconds
+pub fn to_line_protocol(player: &Player, event: Option<&str>) -> String {
+    let mut line = format!("{},id={},group_id={}", INFLUX_MEASUREMENT, player.id, player.group_id);
+    if let Some(event) = event {
+        line.push_str(&format!(",event={}", escape_tag(event)));
+    }
+
+    let mut fields = Vec::new();
+    if let Ok(Value::Object(record)) = serde_json::to_value(player) {
+        for (key, value) in record.iter() {
+            if INFLUX_SKIP_FIELDS.contains(&key.as_str()) {
+                continue;
+            }
+            match value {
+                Value::Number(number) if number.is_f64() => fields.push(format!("{}={}", key, number)),
+                Value::Number(number) => fields.push(format!("{}={}i", key, number)),
+                Value::Bool(flag) => fields.push(format!("{}={}", key, flag)),
+                _ => {}
+            }
+        }
+    }
+
+    line.push(' ');
+    line.push_str(&fields.join(","));
+    line.push(' ');
+    line.push_str(&(player.world_time + ZWIFT_EPOCH).to_string());
+    line
+}
+
+
+pub struct InfluxWriter {
+    config: InfluxConfig,
+    client: Client<HttpsConnector<HttpConnector>>,
+    // lines waiting for write, oldest dropped when full
+    queue: VecDeque<String>,
+    dropped: usize
+}
+
+impl InfluxWriter {
+    pub fn new(config: InfluxConfig) -> Self {
+        InfluxWriter {
+            queue: VecDeque::with_capacity(config.batch_size),
+            config,
+            client: Client::builder().build(
+                HttpsConnectorBuilder::new()
+                    .with_webpki_roots()
+                    .https_or_http()
+                    .enable_http1()
+                    .build()
+            ),
+            dropped: 0
+        }
+    }
+
+    pub fn push(&mut self, player: &Player) {
+        if self.queue.len() >= self.config.max_queue {
+            self.queue.pop_front();
+            self.dropped += 1;
+        }
+        self.queue.push_back(to_line_protocol(player, self.config.event.as_deref()));
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    async fn write(&self, body: String) -> Result<(), String> {
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.config.url)
+            .header("content-type", "text/plain; charset=utf-8");
+        if let Some(token) = &self.config.token {
+            request = request.header("authorization", format!("Token {}", token));
+        }
+        let request = request.body(Body::from(body)).map_err(|err| err.to_string())?;
+        let response = tokio::time::timeout(self.config.timeout, self.client.request(request)).await
+            .map_err(|_| String::from("InfluxDB request timed out"))?
+            .map_err(|err| err.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("InfluxDB response status: {}", response.status()));
+        }
+        Ok(())
+    }
+
+    /// Write all queued lines in batches, lines kept in queue on failure for next retry
+    pub async fn flush(&mut self) -> Result<usize, String> {
+        let mut written = 0;
+        while !self.queue.is_empty() {
+            let count = self.queue.len().min(self.config.batch_size.max(1));
+            let body = self.queue.iter().take(count).cloned().collect::<Vec<String>>().join("\n");
+            self.write(body).await?;
+            self.queue.drain(..count);
+            written += count;
+        }
+        Ok(written)
+    }
+
+    pub async fn run(mut self, mut receiver: Receiver<Vec<Player>>) {
+        let mut interval = tokio::time::interval(self.config.flush_interval);
+        loop {
+            tokio::select! {
+                players = receiver.recv() => match players {
+                    Some(players) => players.iter().for_each(|player| self.push(player)),
+                    None => break
+                },
+                _ = interval.tick() => {
+                    if let Err(err) = self.flush().await {
+                        println!("InfluxDB write error: {}, queued lines: {}", err, self.queue_len());
+                    }
+                }
+            }
+        }
+        if let Err(err) = self.flush().await {
+            println!("InfluxDB write error: {}, lost lines: {}", err, self.queue_len());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::sync::{Arc, Mutex};
+    use warp::Filter;
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn influx_line_protocol() {
+        let player = get_player_instance();
+        let line = to_line_protocol(&player, Some("Race, stage 1"));
+        assert!(line.starts_with(&format!("zwift_player,id={},group_id={},event=Race\\,\\ stage\\ 1 ", player.id, player.group_id)));
+        assert!(line.contains(&format!("power={}i", player.power)));
+        assert!(line.ends_with(&format!(" {}", player.world_time + ZWIFT_EPOCH)));
+        assert!(!line.contains("world_time="));
+        let mut player = get_player_instance();
+        player.world_time = 0;
+        // unix ms of Zwift epoch, 2014-10-22 22:14:35 UTC
+        assert!(to_line_protocol(&player, None).ends_with(" 1414016075000"));
+    }
+
+    #[test]
+    fn influx_bounded_queue() {
+        let mut config = InfluxConfig::new("http://127.0.0.1:1/write");
+        config.max_queue = 3;
+        let mut writer = InfluxWriter::new(config);
+        let player = get_player_instance();
+        for _ in 0..5 {
+            writer.push(&player);
+        }
+        assert_eq!(writer.queue_len(), 3);
+        assert_eq!(writer.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn influx_write_to_server() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_server = received.clone();
+        let route = warp::post()
+            .and(warp::path("write"))
+            .and(warp::body::bytes())
+            .map(move |body: hyper::body::Bytes| {
+                received_server.lock().unwrap().push(String::from_utf8_lossy(&body).to_string());
+                warp::reply()
+            });
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        let mut config = InfluxConfig::new(&format!("http://{}/write", address));
+        config.batch_size = 2;
+        let mut writer = InfluxWriter::new(config);
+        let player = get_player_instance();
+        for _ in 0..3 {
+            writer.push(&player);
+        }
+        assert_eq!(writer.flush().await.unwrap(), 3);
+        assert_eq!(writer.queue_len(), 0);
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].lines().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn influx_write_timeout() {
+        // connection is accepted by OS backlog, but never answered
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = InfluxConfig::new(&format!("http://{}/write", listener.local_addr().unwrap()));
+        config.timeout = Duration::from_millis(100);
+        let mut writer = InfluxWriter::new(config);
+        writer.push(&get_player_instance());
+        assert_eq!(writer.flush().await, Err(String::from("InfluxDB request timed out")));
+        assert_eq!(writer.queue_len(), 1);
+    }
+
+    #[tokio::test]
+    async fn influx_retry_on_failure() {
+        let mut writer = InfluxWriter::new(InfluxConfig::new("http://127.0.0.1:1/write"));
+        writer.push(&get_player_instance());
+        assert!(writer.flush().await.is_err());
+        assert_eq!(writer.queue_len(), 1);
+    }
+}
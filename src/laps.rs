@@ -0,0 +1,262 @@
+use std::collections::{HashMap, VecDeque};
+use serde::Serialize;
+use schemars::JsonSchema;
+use zwift_capture::Player;
+use crate::race::{interpolate_time, Gate};
+
+
+// longer pauses between samples (no data) are not counted
+const LAP_MAX_SAMPLE_TIME: i64 = 3000;
+const LAP_HISTORY_CAPACITY: usize = 100;
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct LapSplit {
+    pub lap: i32,
+    pub start_time: i64,
+    /// none for current lap
+    pub end_time: Option<i64>,
+    /// ms
+    pub time: i64,
+    pub distance: i32,
+    pub average_power: f64,
+    pub average_heartrate: f64,
+    pub average_speed: f64,
+    /// lap was already in progress when rider appeared
+    pub partial: bool
+}
+
+#[derive(Debug,Clone)]
+struct LapAccumulator {
+    lap: i32,
+    start_time: i64,
+    start_distance: i32,
+    last_time: i64,
+    last_distance: i32,
+    // values of the last sample, held until the next one
+    last_values: (f64, f64, f64),
+    partial: bool,
+    // values integrated over sampled time
+    sampled_time: i64,
+    power: f64,
+    heartrate: f64,
+    speed: f64
+}
+
+impl LapAccumulator {
+    fn new(player: &Player, partial: bool) -> Self {
+        LapAccumulator {
+            lap: player.laps,
+            start_time: player.world_time,
+            start_distance: player.distance,
+            last_time: player.world_time,
+            last_distance: player.distance,
+            last_values: (player.power as f64, player.heartrate as f64, player.speed as f64),
+            partial,
+            sampled_time: 0,
+            power: 0.,
+            heartrate: 0.,
+            speed: 0.
+        }
+    }
+
+    /// Next lap from the line crossing, values of the last sample held until the next one
+    fn next_lap(&self, lap: i32) -> Self {
+        LapAccumulator {
+            lap,
+            start_time: self.last_time,
+            start_distance: self.last_distance,
+            last_time: self.last_time,
+            last_distance: self.last_distance,
+            last_values: self.last_values,
+            partial: false,
+            sampled_time: 0,
+            power: 0.,
+            heartrate: 0.,
+            speed: 0.
+        }
+    }
+
+    fn advance(&mut self, world_time: i64, distance: i32) {
+        let duration = (world_time - self.last_time).min(LAP_MAX_SAMPLE_TIME);
+        let (power, heartrate, speed) = self.last_values;
+        self.sampled_time += duration;
+        self.power += power * duration as f64;
+        self.heartrate += heartrate * duration as f64;
+        self.speed += speed * duration as f64;
+        self.last_time = world_time;
+        self.last_distance = distance;
+    }
+
+    fn add_sample(&mut self, player: &Player) {
+        self.advance(player.world_time, player.distance);
+        self.last_values = (player.power as f64, player.heartrate as f64, player.speed as f64);
+    }
+
+    fn average(&self, value: f64) -> f64 {
+        match self.sampled_time > 0 {
+            true => value / self.sampled_time as f64,
+            false => 0.
+        }
+    }
+
+    fn split(&self, end_time: Option<i64>) -> LapSplit {
+        LapSplit {
+            lap: self.lap,
+            start_time: self.start_time,
+            end_time,
+            time: end_time.unwrap_or(self.last_time) - self.start_time,
+            distance: self.last_distance - self.start_distance,
+            average_power: self.average(self.power),
+            average_heartrate: self.average(self.heartrate),
+            average_speed: self.average(self.speed),
+            partial: self.partial
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RiderLaps {
+    last: Player,
+    current: LapAccumulator,
+    completed: VecDeque<LapSplit>
+}
+
+
+/// Lap transitions by `Player.laps` with per-lap splits, for watched and racing riders
+#[derive(Debug,Default)]
+pub struct LapTracker {
+    riders: HashMap<i32, RiderLaps>
+}
+
+impl LapTracker {
+    pub fn new() -> Self {
+        LapTracker::default()
+    }
+
+    /// Latest sample of the rider, older ones are ignored,
+    /// `line` is position of lap line if known
+    pub fn push(&mut self, player: &Player, line: Option<&Gate>) {
+        let rider = match self.riders.get_mut(&player.id) {
+            Some(rider) => rider,
+            _ => {
+                self.riders.insert(player.id, RiderLaps {
+                    last: player.clone(),
+                    current: LapAccumulator::new(player, true),
+                    completed: VecDeque::new()
+                });
+                return;
+            }
+        };
+        if player.world_time <= rider.current.last_time {
+            return;
+        }
+        if player.laps != rider.current.lap {
+            let ratio = line.and_then(|line| line.crossing(&rider.last, player))
+                // line is unknown or not crossed by the move, it's crossed in the middle on average
+                .unwrap_or(0.5);
+            let crossing_time = interpolate_time(&rider.last, player, ratio);
+            let crossing_distance = rider.last.distance + ((player.distance - rider.last.distance) as f64 * ratio).round() as i32;
+            rider.current.advance(crossing_time, crossing_distance);
+            if rider.completed.len() >= LAP_HISTORY_CAPACITY {
+                rider.completed.pop_front();
+            }
+            rider.completed.push_back(rider.current.split(Some(crossing_time)));
+            rider.current = rider.current.next_lap(player.laps);
+        }
+        rider.current.add_sample(player);
+        rider.last = player.clone();
+    }
+
+    pub fn remove(&mut self, player_id: i32) {
+        self.riders.remove(&player_id);
+    }
+
+    pub fn player_ids(&self) -> Vec<i32> {
+        self.riders.keys().cloned().collect()
+    }
+
+    /// End of completed lap, world time of interpolated line crossing
+    pub fn lap_end_time(&self, player_id: i32, lap: i32) -> Option<i64> {
+        self.riders.get(&player_id)?
+            .completed.iter()
+            .rev()
+            .find(|split| split.lap == lap)
+            .and_then(|split| split.end_time)
+    }
+
+    pub fn current_lap(&self, player_id: i32) -> Option<LapSplit> {
+        self.riders.get(&player_id).map(|rider| rider.current.split(None))
+    }
+
+    /// Completed laps, oldest first, then current lap
+    pub fn get(&self, player_id: i32) -> Option<Vec<LapSplit>> {
+        self.riders.get(&player_id).map(|rider| {
+            let mut result: Vec<LapSplit> = rider.completed.iter().cloned().collect();
+            result.push(rider.current.split(None));
+            result
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn lap_tracker_splits() {
+        let mut tracker = LapTracker::new();
+        let base_player = get_player_instance();
+        // new lap every 10 sec, power changes with lap
+        for step in 0..25 {
+            let mut player = base_player.clone();
+            player.world_time = base_player.world_time + step * 1000;
+            player.distance = (step * 100) as _;
+            player.laps = (step / 10) as _;
+            player.power = (200 + (step / 10) * 100) as _;
+            player.heartrate = 150 as _;
+            tracker.push(&player, None);
+        }
+        let laps = tracker.get(base_player.id).unwrap();
+        assert_eq!(laps.len(), 3);
+        assert!(laps[0].partial);
+        assert_eq!(laps[1].lap, 1);
+        assert!(!laps[1].partial);
+        // lines crossed between samples at 9.5 and 19.5 sec
+        assert_eq!(laps[1].start_time, base_player.world_time + 9_500);
+        assert_eq!(laps[1].time, 10_000);
+        assert_eq!(laps[1].distance, 1000);
+        assert_eq!(laps[1].end_time, Some(base_player.world_time + 19_500));
+        assert_eq!(tracker.lap_end_time(base_player.id, 1), laps[1].end_time);
+        // first half second at power of previous lap's last sample
+        assert!((laps[1].average_power - 295.).abs() < 0.001);
+        assert!((laps[1].average_heartrate - 150.).abs() < 0.001);
+        let current = tracker.current_lap(base_player.id).unwrap();
+        assert_eq!(current.lap, 2);
+        assert_eq!(current.end_time, None);
+        assert_eq!(current.time, 4500);
+    }
+
+    #[test]
+    fn lap_tracker_line_crossing() {
+        let mut tracker = LapTracker::new();
+        let base_player = get_player_instance();
+        let line = Gate { x1: 925., y1: -10., x2: 925., y2: 10. };
+        // 10 m/s along x, line at 925 m crossed a quarter into the move from 900 to 1000
+        for step in 0..12 {
+            let mut player = base_player.clone();
+            player.world_time = base_player.world_time + step * 1000;
+            player.x = step as f64 * 100.;
+            player.y = 0.;
+            player.distance = (step * 100) as _;
+            player.laps = if step >= 10 { 1 } else { 0 };
+            tracker.push(&player, Some(&line));
+        }
+        let laps = tracker.get(base_player.id).unwrap();
+        assert_eq!(laps[0].end_time, Some(base_player.world_time + 9_250));
+        assert_eq!(laps[0].distance, 925);
+        assert_eq!(laps[1].start_time, base_player.world_time + 9_250);
+    }
+}
@@ -1,13 +1,55 @@
 use zwift_capture::Player;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use profiles::{PlayerProfile, ProfileRegistry};
+use watch_rules::WatchRule;
+use attacks::AttackDetector;
+use race::RaceTracker;
+use segments::SegmentTracker;
+use points::PointsConfig;
+use teams::TeamConfig;
+use laps::LapTracker;
+use drafting::DraftTracker;
+use spatial::{NearbyMode, NearbyPlayer, SpatialIndex, MAX_NEARBY_RADIUS, ROAD_WIDTH};
 
 
 pub const PLAYER_GROUP_CAPACITY: usize = 10;
 const PLAYER_HISTORY_CAPACITY: usize = 50; // ~ 100ms per player update == 5sec history
 const PLAYER_HISTORY_INTERPOLATION_MAX_TIME_DIFF: i64 = 100;
 const MAX_WORLD_TIME_DIFF: i64 = 5000; // 5 sec
+const PLAYER_DIRECTION_TIME_DIFF: i64 = 1000;
+const SPATIAL_CELL_MARGIN: f64 = 50.;
+/// `Player.road_position` units per second, well above riding speed on the shortest roads,
+/// faster changes are wraparounds at the end of the road or moves to another road
+const ROAD_POSITION_MAX_SPEED: f64 = 100_000.;
+/// Unix time of `Player.world_time` zero, ms (2014-10-22 22:14:35 UTC)
+pub const ZWIFT_EPOCH: i64 = 1_414_016_075_000;
 
 pub mod server;
+pub mod export;
+pub mod storage;
+pub mod metrics;
+pub mod influx;
+pub mod mqtt;
+pub mod alerts;
+pub mod profiles;
+pub mod watch_rules;
+pub mod spatial;
+pub mod drafting;
+pub mod attacks;
+pub mod race;
+pub mod segments;
+pub mod points;
+pub mod teams;
+pub mod laps;
+pub mod map;
+pub mod overlay;
+pub mod decoder;
+
+/// Road position changed faster than riders move between two updates
+pub fn road_position_jump(before: &Player, after: &Player) -> bool {
+    let seconds = (after.world_time - before.world_time).abs().max(1) as f64 / 1000.;
+    (after.road_position - before.road_position).abs() as f64 > ROAD_POSITION_MAX_SPEED * seconds
+}
 
 #[derive(Debug,Default)]
 struct PlayerHistory {
@@ -56,6 +98,10 @@ impl PlayerHistory {
         player.x = before.x + (after.x - before.x) * ratio;
         player.y = before.y + (after.y - before.y) * ratio;
         player.distance = before.distance + ((after.distance - before.distance) as f64 * ratio) as i32;
+        // positions of different roads are not related
+        if !road_position_jump(before, after) {
+            player.road_position = before.road_position + ((after.road_position - before.road_position) as f64 * ratio) as i32;
+        }
 
         player
     }
@@ -94,14 +140,14 @@ impl PlayerHistory {
             },
             // show nearest available data if time difference not so big
             (Some(before), None) => {
-                if before.world_time - time < PLAYER_HISTORY_INTERPOLATION_MAX_TIME_DIFF {
+                if time - before.world_time < PLAYER_HISTORY_INTERPOLATION_MAX_TIME_DIFF {
                     Some(before.clone())
                 } else {
                     None
                 }
             },
             (None, Some(after)) => {
-                if time - after.world_time < PLAYER_HISTORY_INTERPOLATION_MAX_TIME_DIFF {
+                if after.world_time - time < PLAYER_HISTORY_INTERPOLATION_MAX_TIME_DIFF {
                     Some(after.clone())
                 } else {
                     None
@@ -151,6 +197,29 @@ impl PlayerData {
         self.history.get_at_time(time)
     }
 
+    /// Known positions within time range, oldest first
+    pub fn get_positions(&self, from_time: i64, to_time: i64) -> Vec<(f64, f64)> {
+        self.history.data.iter()
+            .rev()
+            .filter(|player| player.world_time >= from_time && player.world_time <= to_time)
+            .map(|player| (player.x, player.y))
+            .collect()
+    }
+
+    /// Normalized direction of travel at given time, from position change over last second
+    pub fn get_direction(&self, time: i64) -> Option<(f64, f64)> {
+        let current = self.history.get_at_time(time)?;
+        let oldest = self.history.data.last()?;
+        let previous = self.history.get_at_time((time - PLAYER_DIRECTION_TIME_DIFF).max(oldest.world_time))?;
+        let dx = current.x - previous.x;
+        let dy = current.y - previous.y;
+        let length = (dx * dx + dy * dy).sqrt();
+        if length < f64::EPSILON {
+            return None;
+        }
+        Some((dx / length, dy / length))
+    }
+
 }
 
 
@@ -224,7 +293,27 @@ pub struct World {
 
     pub players_by_id: HashMap<i32, PlayerData>,
     pub groups_by_id: HashMap<i32, PlayerGroup>,
-    pub group_to_watch: PlayerGroup
+    pub group_to_watch: PlayerGroup,
+    pub profiles: ProfileRegistry,
+    pub watch_rules: Vec<WatchRule>,
+    pub drafting: DraftTracker,
+    pub attacks: AttackDetector,
+    pub race: RaceTracker,
+    pub segments: SegmentTracker,
+    pub points: PointsConfig,
+    pub teams: TeamConfig,
+    pub laps: LapTracker,
+    /// Zwift world (map) of the event, set by user as player states don't carry it
+    pub zwift_world_id: Option<i32>,
+    // road of every player, decoded from server messages
+    road_ids: HashMap<i32, i32>,
+    // event joined by player, decoded from server messages
+    event_ids: HashMap<i32, i64>,
+    // players added to watch group by rules, removed when not match any more
+    watched_by_rules: HashSet<i32>,
+    // players pushed since last analysis update
+    updated_players: HashSet<i32>,
+    spatial_index: SpatialIndex
 }
 
 impl World {
@@ -233,7 +322,22 @@ impl World {
             world_time: 0,
             players_by_id: HashMap::new(),
             groups_by_id: HashMap::new(),
-            group_to_watch: PlayerGroup::new()
+            group_to_watch: PlayerGroup::new(),
+            profiles: ProfileRegistry::new(),
+            watch_rules: Vec::new(),
+            drafting: DraftTracker::new(),
+            attacks: AttackDetector::default(),
+            race: RaceTracker::new(),
+            segments: SegmentTracker::new(),
+            points: PointsConfig::default(),
+            teams: TeamConfig::default(),
+            laps: LapTracker::new(),
+            zwift_world_id: None,
+            road_ids: HashMap::new(),
+            event_ids: HashMap::new(),
+            watched_by_rules: HashSet::new(),
+            updated_players: HashSet::new(),
+            spatial_index: SpatialIndex::default()
         }
     }
 
@@ -256,10 +360,33 @@ impl World {
         }
     }
 
+    fn update_watch_rules(&mut self, player: &Player) {
+        if self.watch_rules.is_empty() && self.watched_by_rules.is_empty() {
+            return;
+        }
+        let profile = self.profiles.get(player.id);
+        let matched = self.watch_rules.iter().any(|rule| rule.matches(player, profile));
+        if matched {
+            if !self.group_to_watch.has_player(player.id) {
+                self.group_to_watch.add_player(player.id);
+                self.watched_by_rules.insert(player.id);
+            }
+        } else if self.watched_by_rules.remove(&player.id) {
+            self.group_to_watch.remove_player(player.id);
+        }
+    }
+
     pub fn push_player(&mut self, player: Player) -> Option<i64> {
         let player_id = player.id;
         let group_id = player.group_id;
         let world_time = player.world_time;
+        let (x, y) = (player.x, player.y);
+
+        self.update_watch_rules(&player);
+        if self.tracks_laps(player_id, group_id) {
+            let line = self.race.config().and_then(|config| config.start_line.as_ref());
+            self.laps.push(&player, line);
+        }
 
         if let Some(player_data) = self.players_by_id.get_mut(&player.id) {
             let _ = player_data.update(player);
@@ -267,9 +394,15 @@ impl World {
             let player_data = PlayerData::new(player);
             self.players_by_id.insert(player_id, player_data);
         }
+        if let Some(player_data) = self.players_by_id.get(&player_id) {
+            if player_data.world_time == world_time {
+                self.spatial_index.update(player_id, x, y);
+            }
+        }
 
         self.update_players_group(group_id, player_id);
         self.update_world_time(world_time);
+        self.updated_players.insert(player_id);
 
         Some(world_time)
     }
@@ -281,13 +414,94 @@ impl World {
                 result.push(time);
             }
         }
+        self.update_analysis();
         Some(result)
     }
 
+    /// Profiles decoded from server messages, see `decoder::decode_server_message`.
+    /// Changes are saved to file by background task with `ProfileRegistry::take_unsaved`
+    pub fn push_profiles_batch(&mut self, profiles: Vec<(i32, PlayerProfile)>) -> usize {
+        profiles.iter()
+            .filter(|(player_id, profile)| self.profiles.push_decoded(*player_id, profile))
+            .count()
+    }
+
+    /// Roads of players decoded from server messages, see `decoder::decode_server_message`
+    pub fn push_road_ids(&mut self, road_ids: Vec<(i32, i32)>) {
+        self.road_ids.extend(road_ids);
+    }
+
+    pub fn get_road_id(&self, player_id: i32) -> Option<i32> {
+        self.road_ids.get(&player_id).cloned()
+    }
+
+    /// Players joined (`Some(event id)`) or left (`None`) event, decoded from server messages
+    pub fn push_events(&mut self, events: Vec<(i32, Option<i64>)>) {
+        for (player_id, event_id) in events.into_iter() {
+            match event_id {
+                Some(event_id) => self.event_ids.insert(player_id, event_id),
+                None => self.event_ids.remove(&player_id)
+            };
+        }
+    }
+
+    pub fn get_event_id(&self, player_id: i32) -> Option<i64> {
+        self.event_ids.get(&player_id).cloned()
+    }
+
+    /// Event joined by every player known to be in event
+    pub fn event_ids(&self) -> &HashMap<i32, i64> {
+        &self.event_ids
+    }
+
+    /// Laps are tracked for watch group and riders of current race,
+    /// started riders keep lap state when they leave watch group
+    fn tracks_laps(&self, player_id: i32, group_id: i32) -> bool {
+        self.group_to_watch.has_player(player_id)
+            || self.race.has_rider(player_id)
+            || self.race.config().map(|config| config.group_id == Some(group_id)).unwrap_or(false)
+    }
+
+    /// Trackers which need whole world state, sampled by world time
+    fn update_analysis(&mut self) {
+        for player_id in self.laps.player_ids().into_iter() {
+            let group_id = self.players_by_id.get(&player_id)
+                .and_then(|player_data| player_data.get_latest())
+                .map(|player| player.group_id);
+            if !group_id.map(|group_id| self.tracks_laps(player_id, group_id)).unwrap_or(false) {
+                self.laps.remove(player_id);
+            }
+        }
+
+        let mut drafting = std::mem::take(&mut self.drafting);
+        drafting.update(self);
+        self.drafting = drafting;
+
+        let mut attacks = std::mem::take(&mut self.attacks);
+        attacks.update(self);
+        self.attacks = attacks;
+
+        let updated_players = std::mem::take(&mut self.updated_players);
+        let mut race = std::mem::take(&mut self.race);
+        race.update(self, &updated_players);
+        self.race = race;
+
+        let mut segments = std::mem::take(&mut self.segments);
+        segments.update(self);
+        self.segments = segments;
+    }
+
     pub fn clear_player(&mut self, player_id: i32) {
         for (_, group) in self.groups_by_id.iter_mut() {
             group.remove_player(player_id);
         }
+        if self.watched_by_rules.remove(&player_id) {
+            self.group_to_watch.remove_player(player_id);
+        }
+        self.spatial_index.remove(player_id);
+        self.laps.remove(player_id);
+        self.road_ids.remove(&player_id);
+        self.event_ids.remove(&player_id);
         if self.players_by_id.contains_key(&player_id) {
             self.players_by_id.remove(&player_id);
         }
@@ -333,12 +547,110 @@ impl World {
         min_time
     }
 
+    pub fn get_group_to_watch_data(&self, latest: bool) -> Vec<Player> {
+        let mut result = Vec::with_capacity(PLAYER_GROUP_CAPACITY);
+        let latest_time = match latest {
+            true => self.world_time,
+            false => self.get_latest_world_time_for_group(&self.group_to_watch)
+        };
+        for player_id in self.group_to_watch.iter() {
+            if let Some(player) = self.get_player_data(player_id) {
+                if let Some(data) = match latest {
+                    true => player.get_latest(),
+                    false => player.get_at_time(latest_time)
+                } {
+                    result.push(data);
+                }
+            }
+        }
+        result
+    }
+
+    /// Riders within radius around player at synchronized time, nearest first
+    pub fn find_nearby_players(&self, player_id: i32, radius: f64, mode: NearbyMode) -> Option<Vec<NearbyPlayer>> {
+        let player_data = self.get_player_data(player_id)?;
+        let latest = player_data.get_latest()?;
+        let radius = radius.min(MAX_NEARBY_RADIUS);
+        // latest positions in index may be ahead of synchronized time
+        let search_radius = radius + SPATIAL_CELL_MARGIN;
+        let candidates: Vec<i32> = self.spatial_index.query(latest.x, latest.y, search_radius)
+            .into_iter()
+            .filter(|&id| id != player_id)
+            .collect();
+
+        let mut group = PlayerGroup::from(&candidates);
+        group.add_player(player_id);
+        let time = self.get_latest_world_time_for_group(&group);
+        let player = player_data.get_at_time(time)?;
+        let direction = match mode {
+            NearbyMode::Road => player_data.get_direction(time),
+            NearbyMode::Euclidean => None
+        };
+        let road_id = self.get_road_id(player_id);
+
+        let mut result = Vec::new();
+        for id in candidates.into_iter() {
+            let other = match self.get_player_data(id).and_then(|other| other.get_at_time(time)) {
+                Some(other) => other,
+                _ => continue
+            };
+            let distance = spatial::euclidean_distance(&player, &other);
+            // crossing or parallel roads, when roads of both riders are known
+            let other_road_id = self.get_road_id(id);
+            if mode == NearbyMode::Road && road_id.is_some() && other_road_id.is_some() && other_road_id != road_id {
+                continue;
+            }
+            let gap = match direction {
+                Some(direction) => {
+                    let (along, side) = spatial::road_gap(&player, &other, direction);
+                    if side > ROAD_WIDTH {
+                        continue;
+                    }
+                    along
+                },
+                None => distance
+            };
+            if gap.abs() <= radius {
+                result.push(NearbyPlayer { id, distance, gap, player: other });
+            }
+        }
+        result.sort_by(|a, b| a.gap.abs().partial_cmp(&b.gap.abs()).unwrap_or(std::cmp::Ordering::Equal));
+        Some(result)
+    }
+
     pub fn add_player_to_watch(&mut self, player_id: i32) {
         self.group_to_watch.add_player(player_id);
     }
 
+    pub fn remove_player_from_watch(&mut self, player_id: i32) {
+        self.group_to_watch.remove_player(player_id);
+    }
+
     pub fn clear_group_to_watch(&mut self) {
         self.group_to_watch.clear();
+        self.watched_by_rules.clear();
+    }
+
+    pub fn add_watch_rule(&mut self, rule: WatchRule) {
+        if !self.watch_rules.contains(&rule) {
+            self.watch_rules.push(rule);
+        }
+        self.apply_watch_rules();
+    }
+
+    pub fn clear_watch_rules(&mut self) {
+        self.watch_rules.clear();
+        self.apply_watch_rules();
+    }
+
+    /// Check rules against latest data of all known players
+    pub fn apply_watch_rules(&mut self) {
+        let players: Vec<Player> = self.players_by_id.values()
+            .filter_map(|player_data| player_data.get_latest())
+            .collect();
+        for player in players.iter() {
+            self.update_watch_rules(player);
+        }
     }
 
 }
@@ -351,7 +663,7 @@ mod tests {
     use zwift_capture::ZwiftMessage;
     use super::*;
 
-    fn get_player_instance() -> Player {
+    pub(crate) fn get_player_instance() -> Player {
         // some sample captured data
         let packet_payload = hex!("0686a9010008011086d30618e1a6fbcce80520ab023a6e0886d30610e1a6fbcce8051800208fac3a2800300040f4fa860548005000584f600068cbd5aa0170c0843d7800800100980195809808a0018f808008a80100b80100c00100cd01ae378847d50119191a46dd01a0d52ec7e00186d306e80100f80100950200000000980206b002001f403176");
         let message = ZwiftMessage::ToServer(&packet_payload);
@@ -368,6 +680,49 @@ mod tests {
         assert_eq!(world.world_time, player_two.world_time);
     }
 
+    #[test]
+    fn world_laps_tracked_riders() {
+        let mut world = World::new();
+        let mut player = get_player_instance();
+        let player_id = player.id;
+        world.push_players_batch(vec![player.clone()]);
+        assert!(world.laps.get(player_id).is_none());
+        world.add_player_to_watch(player_id);
+        player.world_time += 1000;
+        world.push_players_batch(vec![player.clone()]);
+        assert!(world.laps.get(player_id).is_some());
+        world.remove_player_from_watch(player_id);
+        world.push_players_batch(Vec::new());
+        assert!(world.laps.get(player_id).is_none());
+    }
+
+    #[test]
+    fn world_laps_kept_for_race_riders() {
+        let mut world = World::new();
+        let mut player = get_player_instance();
+        let player_id = player.id;
+        world.race.start(race::RaceConfig {
+            name: None,
+            start_time: Some(player.world_time),
+            start_line: None,
+            finish: race::RaceFinish::Laps { laps: 2 },
+            group_id: None
+        }, player.world_time);
+        world.add_player_to_watch(player_id);
+        for _ in 0..2 {
+            player.world_time += 1000;
+            world.push_players_batch(vec![player.clone()]);
+        }
+        assert!(world.race.has_rider(player_id));
+        world.remove_player_from_watch(player_id);
+        player.world_time += 1000;
+        world.push_players_batch(vec![player.clone()]);
+        assert!(world.laps.get(player_id).is_some());
+        world.race.stop();
+        world.push_players_batch(Vec::new());
+        assert!(world.laps.get(player_id).is_none());
+    }
+
     #[test]
     fn world_clear_player() {
         let mut world = World::new();
@@ -376,6 +731,73 @@ mod tests {
         assert_eq!(world.find_outdated_players().unwrap().len(), 1);
     }
 
+    #[test]
+    fn world_watch_rules() {
+        let mut world = World::new();
+        let player = get_player_instance();
+        let player_id = player.id;
+        let mut other = get_player_instance();
+        other.id = player_id + 1;
+        other.group_id = player.group_id + 1;
+        world.push_player(player.clone());
+        world.add_player_to_watch(other.id);
+        world.push_player(other.clone());
+
+        world.add_watch_rule(WatchRule::Group { group_id: player.group_id });
+        assert!(world.group_to_watch.has_player(player_id));
+        assert!(world.group_to_watch.has_player(other.id));
+
+        // left the group
+        let mut moved = player.clone();
+        moved.group_id = player.group_id + 2;
+        world.push_player(moved);
+        assert!(!world.group_to_watch.has_player(player_id));
+
+        // back to group, then clear as outdated
+        world.push_player(player);
+        assert!(world.group_to_watch.has_player(player_id));
+        world.clear_player(player_id);
+        assert!(!world.group_to_watch.has_player(player_id));
+
+        // manually added player stays in watch group
+        world.clear_watch_rules();
+        assert!(world.group_to_watch.has_player(other.id));
+    }
+
+    #[test]
+    fn world_find_nearby_players() {
+        let mut world = World::new();
+        let base_player = get_player_instance();
+        // riders on the same road along x axis, and one far away
+        for (id, x, y) in [(1, 0., 0.), (2, 30., 0.), (3, -10., 2.), (4, 0., 40.), (5, 5000., 0.)].iter() {
+            for step in 0..3 {
+                let mut player = base_player.clone();
+                player.id = *id;
+                player.world_time = base_player.world_time + step * 500;
+                player.x = x + step as f64 * 5.;
+                player.y = *y;
+                world.push_player(player);
+            }
+        }
+        let nearby = world.find_nearby_players(1, 50., NearbyMode::Euclidean).unwrap();
+        let ids: Vec<i32> = nearby.iter().map(|nearby| nearby.id).collect();
+        assert_eq!(ids, vec![3, 2, 4]);
+
+        let nearby = world.find_nearby_players(1, 50., NearbyMode::Road).unwrap();
+        let gaps: Vec<(i32, f64)> = nearby.iter().map(|nearby| (nearby.id, nearby.gap)).collect();
+        assert_eq!(gaps, vec![(3, -10.), (2, 30.)]);
+
+        // rider on other road is skipped, unknown road is kept
+        world.push_road_ids(vec![(1, 5), (2, 7)]);
+        let nearby = world.find_nearby_players(1, 50., NearbyMode::Road).unwrap();
+        let ids: Vec<i32> = nearby.iter().map(|nearby| nearby.id).collect();
+        assert_eq!(ids, vec![3]);
+        let nearby = world.find_nearby_players(1, 50., NearbyMode::Euclidean).unwrap();
+        assert_eq!(nearby.len(), 3);
+
+        assert!(world.find_nearby_players(100, 50., NearbyMode::Road).is_none());
+    }
+
     #[test]
     fn user_group_iter() {
         let mut group = PlayerGroup::new();
@@ -446,10 +868,12 @@ mod tests {
         one.time = 0;
         one.x = 0.;
         one.distance = 100;
+        one.road_position = 1000;
         two.world_time = 100;
         two.time = 100;
         two.x = 100.;
         two.distance = 200;
+        two.road_position = 3000;
         player_history.push(one);
         player_history.push(two);
         let mid = player_history.get_at_time(50).unwrap();
@@ -457,5 +881,33 @@ mod tests {
         assert_eq!(mid.time, 0);
         assert_eq!(mid.x, 50.);
         assert_eq!(mid.distance, 150);
+        assert_eq!(mid.road_position, 2000);
+        // road changed, position is not interpolated
+        let mut three = player_history.get_at_time(100).unwrap();
+        three.world_time = 200;
+        three.road_position = 900_000;
+        player_history.push(three);
+        assert_eq!(player_history.get_at_time(150).unwrap().road_position, 3000);
+        assert_eq!(player_history.get_at_time(200).unwrap().road_position, 900_000);
+    }
+
+    #[test]
+    fn player_history_get_at_time_outside_range() {
+        let mut player_history = PlayerHistory::new();
+        let base_player = get_player_instance();
+        let mut one = base_player.clone();
+        let mut two = base_player.clone();
+        one.world_time = 1000;
+        two.world_time = 2000;
+        player_history.push(one);
+        player_history.push(two);
+        // nearest sample is returned only close to known range, on both sides
+        let max_diff = PLAYER_HISTORY_INTERPOLATION_MAX_TIME_DIFF;
+        assert_eq!(player_history.get_at_time(2000 + max_diff - 1).unwrap().world_time, 2000);
+        assert!(player_history.get_at_time(2000 + max_diff).is_none());
+        assert!(player_history.get_at_time(10_000).is_none());
+        assert_eq!(player_history.get_at_time(1000 - max_diff + 1).unwrap().world_time, 1000);
+        assert!(player_history.get_at_time(1000 - max_diff).is_none());
+        assert!(player_history.get_at_time(0).is_none());
     }
 }
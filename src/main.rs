@@ -1,25 +1,275 @@
-use chrono::prelude::DateTime;
-use chrono::Utc;
 use pcap::Device;
 use std::io::stdin;
-use std::iter::FromIterator;
+use std::net::SocketAddr;
 use std::path;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use warp::Filter;
-use zwift_capture::{Player, ZwiftCapture};
-use zwift_watcher::server::{handlers, models, Routes};
-use zwift_watcher::{PlayerData, PlayerGroup, World, PLAYER_GROUP_CAPACITY};
+use std::time::Duration;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use structopt::StructOpt;
+use zwift_capture::{ZwiftCapture, ZwiftMessage};
+use zwift_watcher::export::{format_world_time, ExportFilter, ExportFormat, Exporter};
+use zwift_watcher::server::auth::{Auth, Scope};
+use zwift_watcher::server::tls::{self, TlsConfig};
+use zwift_watcher::server::Routes;
+use zwift_watcher::alerts::{AlertConfig, AlertEngine, WebhookSender, ALERT_CHANNEL_CAPACITY};
+use zwift_watcher::influx::{InfluxConfig, InfluxWriter, INFLUX_CHANNEL_CAPACITY};
+use zwift_watcher::metrics::Metrics;
+use zwift_watcher::mqtt::{self, MqttConfig};
+use zwift_watcher::overlay::OverlayTemplates;
+use zwift_watcher::decoder::{self, ZWIFT_UDP_PORT};
+use zwift_watcher::profiles::ProfileRegistry;
+use zwift_watcher::storage::{self, Storage, StorageWrite, STORAGE_CHANNEL_CAPACITY};
+use zwift_watcher::World;
 
 const TICK: i64 = 1000;
+const PROFILES_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(StructOpt, Debug, Clone)]
+struct Cli {
+    /// SQLite database file to store player samples and session summaries
+    #[structopt(long)]
+    database: Option<String>,
+    /// InfluxDB write url for player updates, e.g. http://localhost:8086/write?db=zwift&precision=ms
+    #[structopt(long)]
+    influx_url: Option<String>,
+    #[structopt(long)]
+    influx_token: Option<String>,
+    /// value of `event` tag for InfluxDB lines
+    #[structopt(long)]
+    influx_event: Option<String>,
+    /// MQTT broker host to publish watch group telemetry
+    #[structopt(long)]
+    mqtt_host: Option<String>,
+    #[structopt(long, default_value = "1883")]
+    mqtt_port: u16,
+    #[structopt(long, default_value = "0", possible_values = &["0", "1", "2"])]
+    mqtt_qos: u8,
+    #[structopt(long)]
+    mqtt_retain: bool,
+    /// accept watch group changes on MQTT command topic, broker clients bypass API tokens
+    #[structopt(long)]
+    mqtt_commands: bool,
+    /// JSON file with player profiles (name, team, weight, FTP, max heart rate, country)
+    #[structopt(long)]
+    profiles: Option<String>,
+    /// JSON file with alert rules and webhook urls
+    #[structopt(long)]
+    alerts: Option<String>,
+    /// Zwift world of the event, segments with other `world_id` are skipped
+    #[structopt(long)]
+    world_id: Option<i32>,
+    /// directory with overlay `.html` templates served at /overlay/{template}
+    #[structopt(long)]
+    overlays: Option<String>,
+    /// address of HTTP API
+    #[structopt(long, default_value = "127.0.0.1:3030")]
+    bind: SocketAddr,
+    /// JSON file with API tokens, `[{"token": "...", "scope": "read_only" | "admin"}]`
+    #[structopt(long)]
+    tokens: Option<String>,
+    /// API token with admin scope, API is open if no tokens set
+    #[structopt(long)]
+    admin_token: Vec<String>,
+    /// API token with read only scope
+    #[structopt(long)]
+    read_token: Vec<String>,
+    /// PEM certificate chain to serve API over HTTPS, re-read on SIGHUP
+    #[structopt(long, requires = "tls-key")]
+    tls_cert: Option<String>,
+    /// PEM private key for `--tls-cert`
+    #[structopt(long, requires = "tls-cert")]
+    tls_key: Option<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug, Clone)]
+enum Command {
+    /// Export every decoded player record from `.pcap` file, without starting the server
+    Export {
+        dump_file: String,
+        /// csv or jsonl
+        #[structopt(short, long, default_value = "csv")]
+        format: ExportFormat,
+        /// output file, stdout if not set
+        #[structopt(short, long)]
+        output: Option<String>,
+        #[structopt(long = "player")]
+        player_ids: Vec<i32>,
+        #[structopt(long = "group")]
+        group_ids: Vec<i32>,
+        #[structopt(long)]
+        from_time: Option<i64>,
+        #[structopt(long)]
+        to_time: Option<i64>,
+    },
+}
+
+fn export_dump(
+    dump_file: &str,
+    format: ExportFormat,
+    output: Option<String>,
+    filter: ExportFilter,
+) -> io::Result<usize> {
+    let writer: Box<dyn Write> = match output {
+        Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+        _ => Box::new(BufWriter::new(io::stdout())),
+    };
+    let mut exporter = Exporter::new(writer, format, filter);
+    let mut capture = ZwiftCapture::from_file(path::Path::new(dump_file));
+    for players in &mut capture {
+        exporter.write_batch(&players)?;
+    }
+    let count = exporter.count();
+    exporter.finish()?;
+    Ok(count)
+}
+
+/// Rider names and roads from server messages, `ZwiftCapture` yields only decoded player states
+/// so server packets are read with second capture on the same device
+fn capture_server_messages(device: Device, world: Arc<Mutex<World>>) -> Result<(), pcap::Error> {
+    let mut capture = pcap::Capture::from_device(device)?.timeout(1000).open()?;
+    capture.filter(&format!("udp src port {}", ZWIFT_UDP_PORT))?;
+    let linktype = capture.get_datalink();
+    loop {
+        let packet = match capture.next() {
+            Ok(packet) => packet,
+            Err(pcap::Error::TimeoutExpired) => continue,
+            Err(err) => return Err(err),
+        };
+        if let Some(payload) = decoder::udp_payload(linktype, packet.data) {
+            let update = decoder::decode_server_message(&ZwiftMessage::FromServer(payload));
+            let mut world = world.lock().unwrap();
+            if !update.profiles.is_empty() {
+                world.push_profiles_batch(update.profiles);
+            }
+            world.push_road_ids(update.road_ids);
+            world.push_events(update.events);
+        }
+    }
+}
+
+/// Write decoded profiles to file outside of world lock
+async fn save_profiles(world: &Arc<Mutex<World>>) {
+    let unsaved = world.lock().unwrap().profiles.take_unsaved();
+    if let Some(registry) = unsaved {
+        match tokio::task::spawn_blocking(move || registry.save()).await {
+            Ok(Err(err)) => println!("Profiles save error: {}", err),
+            Err(err) => println!("Profiles save error: {}", err),
+            _ => {}
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
+    let args = Cli::from_args();
+
+    if let Some(Command::Export {
+        dump_file,
+        format,
+        output,
+        player_ids,
+        group_ids,
+        from_time,
+        to_time,
+    }) = args.command
+    {
+        let filter = ExportFilter {
+            player_ids,
+            group_ids,
+            from_time,
+            to_time,
+        };
+        match export_dump(&dump_file, format, output, filter) {
+            Ok(count) => eprintln!("Exported records: {}", count),
+            Err(err) => {
+                eprintln!("Export failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     println!("Start!");
-    let world = Arc::new(Mutex::new(World::new()));
+    let mut world = World::new();
+    world.zwift_world_id = args.world_id;
+    if let Some(path) = &args.profiles {
+        world.profiles = match ProfileRegistry::from_file(&path) {
+            Ok(profiles) => profiles,
+            Err(err) => {
+                println!("Profiles file error: {}", err);
+                std::process::exit(1);
+            }
+        };
+        println!("Player profiles: {}", world.profiles.all().len());
+    }
+    let world = Arc::new(Mutex::new(world));
     let world_capture = world.clone();
+    let world_profiles = world.clone();
+    let world_shutdown = world.clone();
+
+    if args.profiles.is_some() {
+        let world = world.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PROFILES_SAVE_INTERVAL);
+            loop {
+                interval.tick().await;
+                save_profiles(&world).await;
+            }
+        });
+    }
+
+    let storage = args.database.map(|database| {
+        println!("Open database: {}", &database);
+        Arc::new(Mutex::new(Storage::open(&database).unwrap()))
+    });
+    let storage_sender = storage.clone().map(|storage| {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(STORAGE_CHANNEL_CAPACITY);
+        thread::spawn(move || storage::run_writer(storage, receiver));
+        sender
+    });
+
+    let metrics = Arc::new(Metrics::new());
+    let metrics_capture = metrics.clone();
+
+    if let Some(host) = args.mqtt_host {
+        println!("MQTT output: {}:{}", &host, args.mqtt_port);
+        let mut config = MqttConfig::new(&host, args.mqtt_port);
+        config.qos = args.mqtt_qos;
+        config.retain = args.mqtt_retain;
+        config.commands = args.mqtt_commands;
+        tokio::spawn(mqtt::run(config, world.clone()));
+    }
+
+    let mut influx_sender = None;
+    if let Some(url) = args.influx_url {
+        println!("InfluxDB output: {}", &url);
+        let mut config = InfluxConfig::new(&url);
+        config.token = args.influx_token;
+        config.event = args.influx_event;
+        let (sender, receiver) = tokio::sync::mpsc::channel(INFLUX_CHANNEL_CAPACITY);
+        tokio::spawn(InfluxWriter::new(config).run(receiver));
+        influx_sender = Some(sender);
+    }
+
+    let mut alerts = None;
+    if let Some(path) = args.alerts {
+        let config = match AlertConfig::from_file(&path) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("Alert config error: {}", err);
+                std::process::exit(1);
+            }
+        };
+        println!("Alert rules: {}, webhooks: {}", config.rules.len(), config.webhooks.len());
+        let (sender, receiver) = tokio::sync::mpsc::channel(ALERT_CHANNEL_CAPACITY);
+        tokio::spawn(WebhookSender::new(config.webhooks).run(receiver));
+        alerts = Some((AlertEngine::new(config.rules), sender));
+    }
 
     let capture_thread = thread::spawn(move || {
         let mut counter: i64 = 0;
@@ -39,41 +289,159 @@ async fn main() {
         let choice: usize = input_str.trim().parse().unwrap();
         let selected_device = devices_list.remove(choice);
 
+        let profiles_device = selected_device.clone();
+        thread::spawn(move || loop {
+            if let Err(err) = capture_server_messages(profiles_device.clone(), world_profiles.clone()) {
+                println!("Server messages capture error: {}", err);
+                thread::sleep(Duration::from_secs(1));
+            }
+        });
+
+        let mut reopen = false;
         loop {
+            if reopen {
+                metrics_capture.record_device_reopen();
+            }
+            reopen = true;
             println!("Open device: {:?}", &selected_device);
             let mut capture = ZwiftCapture::from_device(selected_device.clone());
             for players in &mut capture {
                 // .skip(20000) {
+                if let Some(sender) = &storage_sender {
+                    // capture thread never waits for database writes
+                    if sender.try_send(StorageWrite::Players(players.clone())).is_err() {
+                        println!("Players dropped, storage queue is full");
+                    }
+                }
+                if let Some(sender) = &influx_sender {
+                    // capture thread never waits for InfluxDB writer
+                    if sender.try_send(players.clone()).is_err() {
+                        metrics_capture.record_influx_dropped(players.len());
+                    }
+                }
+                let players_count = players.len();
+                let lock_start = time::Instant::now();
                 let mut world_capture = world_capture.lock().unwrap();
+                metrics_capture.record_batch(players_count, lock_start.elapsed());
                 let _times = world_capture.push_players_batch(players).unwrap();
+                metrics_capture.set_active_players(world_capture.players_by_id.len());
+                if let Some((engine, sender)) = &mut alerts {
+                    for alert in engine.evaluate(&world_capture) {
+                        // capture thread never waits for webhooks
+                        if sender.try_send(alert).is_err() {
+                            println!("Alert dropped, webhook queue is full");
+                        }
+                    }
+                }
 
                 counter += 1;
                 if counter % TICK == 0 {
-                    let st = UNIX_EPOCH + Duration::from_millis(world_capture.world_time as u64);
-                    let datetime = DateTime::<Utc>::from(st);
+                    metrics_capture.tick();
                     println!(
                         "Tick {}, time: [{}] {}",
                         counter,
                         world_capture.world_time,
-                        datetime.format("%Y-%m-%d %H:%M:%S.%f").to_string()
+                        format_world_time(world_capture.world_time)
                     );
                     if let Some(outdated) = world_capture.find_outdated_players() {
                         println!("Outdated players: {}", outdated.len());
+                        metrics_capture.record_evicted(outdated.len());
+                        if let Some((engine, sender)) = &mut alerts {
+                            for alert in engine.players_disconnected(&world_capture, &outdated) {
+                                // capture thread never waits for webhooks
+                                if sender.try_send(alert).is_err() {
+                                    println!("Alert dropped, webhook queue is full");
+                                }
+                            }
+                        }
                         outdated
                             .iter()
-                            .for_each(|&id| world_capture.clear_player(id))
+                            .for_each(|&id| world_capture.clear_player(id));
+                        if let Some(sender) = &storage_sender {
+                            if sender.try_send(StorageWrite::CloseSessions(outdated.clone())).is_err() {
+                                println!("Session close dropped, storage queue is full");
+                            }
+                        }
                     }
                 }
             }
             capture.print_stat();
+            // iterator doesn't report why it ended, device failed only if it's gone from the list
+            let available = Device::list()
+                .map(|devices| devices.iter().any(|device| device.name == selected_device.name))
+                .unwrap_or(false);
+            if !available {
+                println!("Capture device unavailable: {}", &selected_device.name);
+                metrics_capture.record_capture_error();
+            }
             println!("Close device");
         }
         println!("Capture thread: done")
     });
 
-    let routes = Routes::new(world).generate();
-    let _ = warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
+    let mut routes = Routes::new(world).with_metrics(metrics);
+    if let Some(storage) = &storage {
+        routes = routes.with_storage(storage.clone());
+    }
+    if let Some(overlays) = args.overlays {
+        routes = routes.with_overlays(OverlayTemplates::new(overlays));
+    }
+    let mut auth = match args.tokens {
+        Some(path) => Auth::from_file(&path).unwrap(),
+        _ => Auth::new(),
+    };
+    for token in args.admin_token.iter() {
+        auth.add_token(token, Scope::Admin);
+    }
+    for token in args.read_token.iter() {
+        auth.add_token(token, Scope::ReadOnly);
+    }
+    if auth.is_enabled() {
+        println!("API token authentication enabled");
+    } else if !args.bind.ip().is_loopback() {
+        println!("Warning: API is open without tokens on {}", args.bind);
+    }
+    let routes = routes.with_auth(auth).generate();
+    let bind = args.bind;
+    let tls_files = (args.tls_cert, args.tls_key);
+    // false if server failed to start
+    let server = async move {
+        match tls_files {
+            (Some(cert), Some(key)) => {
+                println!("HTTPS API: {}", bind);
+                match tls::serve(routes, bind, TlsConfig::new(&cert, &key)).await {
+                    Ok(()) => true,
+                    Err(err) => {
+                        println!("HTTPS server error: {}", err);
+                        false
+                    }
+                }
+            }
+            _ => {
+                warp::serve(routes).run(bind).await;
+                true
+            }
+        }
+    };
+    let served = tokio::select! {
+        served = server => served,
+        _ = tokio::signal::ctrl_c() => {
+            println!("Shutdown");
+            true
+        },
+    };
 
-    let _ = capture_thread.join();
+    save_profiles(&world_shutdown).await;
+    // capture thread never ends on live device, sessions are closed with last known samples
+    if let Some(storage) = storage {
+        match storage.lock().unwrap().close_all_sessions() {
+            Ok(count) => println!("Closed sessions: {}", count),
+            Err(err) => println!("Database error: {}", err)
+        }
+    }
+    drop(capture_thread);
     println!("End!");
+    if !served {
+        std::process::exit(1);
+    }
 }
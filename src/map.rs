@@ -0,0 +1,201 @@
+use std::fmt::Write;
+use crate::{PlayerGroup, World};
+
+
+const MAP_PADDING: f64 = 20.;
+/// smallest image width and height, leaves room for drawing inside the padding
+pub const MAP_MIN_SIZE: u32 = 100;
+// bounds for a single rider or riders at the same point, meters
+const MAP_MIN_SPAN: f64 = 100.;
+const MAP_TRAIL_TIME: i64 = 5000;
+const MAP_DOT_RADIUS: f64 = 4.;
+const MAP_WATCH_DOT_RADIUS: f64 = 6.;
+
+#[derive(Debug,Clone)]
+pub struct MapOptions {
+    pub width: u32,
+    pub height: u32,
+    /// positions for last 5 sec from player history
+    pub trails: bool,
+    pub labels: bool,
+    /// only riders of watch group, otherwise all riders with watch group highlighted
+    pub watch_only: bool
+}
+
+impl Default for MapOptions {
+    fn default() -> Self {
+        MapOptions {
+            width: 800,
+            height: 600,
+            trails: false,
+            labels: true,
+            watch_only: false
+        }
+    }
+}
+
+struct MapRider {
+    id: i32,
+    watched: bool,
+    position: (f64, f64),
+    trail: Vec<(f64, f64)>
+}
+
+fn escape_xml(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Transform from world coordinates to image, fits all points
+struct Projection {
+    min_x: f64,
+    max_y: f64,
+    scale: f64,
+    offset: (f64, f64)
+}
+
+impl Projection {
+    fn fit<'a, I: Iterator<Item = &'a (f64, f64)>>(points: I, width: f64, height: f64) -> Self {
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (f64::MAX, f64::MAX, f64::MIN, f64::MIN);
+        for &(x, y) in points {
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+        }
+        if min_x > max_x {
+            min_x = 0.;
+            min_y = 0.;
+            max_x = 0.;
+            max_y = 0.;
+        }
+        let span_x = (max_x - min_x).max(MAP_MIN_SPAN);
+        let span_y = (max_y - min_y).max(MAP_MIN_SPAN);
+        let center = ((min_x + max_x) / 2., (min_y + max_y) / 2.);
+        let scale = ((width - 2. * MAP_PADDING) / span_x).min((height - 2. * MAP_PADDING) / span_y);
+        let min_x = center.0 - span_x / 2.;
+        let max_y = center.1 + span_y / 2.;
+        // center the fitted area in the image
+        let offset = (
+            (width - span_x * scale) / 2.,
+            (height - span_y * scale) / 2.
+        );
+        Projection { min_x, max_y, scale, offset }
+    }
+
+    /// y axis points up on the map
+    fn project(&self, point: (f64, f64)) -> (f64, f64) {
+        (
+            self.offset.0 + (point.0 - self.min_x) * self.scale,
+            self.offset.1 + (self.max_y - point.1) * self.scale
+        )
+    }
+}
+
+pub fn render_svg(world: &World, options: &MapOptions) -> String {
+    let group = match options.watch_only {
+        true => world.group_to_watch.clone(),
+        false => PlayerGroup::from(&world.get_players_list().unwrap_or_default())
+    };
+    let time = world.get_latest_world_time_for_group(&group);
+
+    let mut riders = Vec::new();
+    for player_id in group.iter() {
+        let player_data = match world.get_player_data(player_id) {
+            Some(player_data) => player_data,
+            _ => continue
+        };
+        let player = match player_data.get_at_time(time).or_else(|| player_data.get_latest()) {
+            Some(player) => player,
+            _ => continue
+        };
+        let trail = match options.trails {
+            true => player_data.get_positions(player.world_time - MAP_TRAIL_TIME, player.world_time),
+            false => Vec::new()
+        };
+        riders.push(MapRider {
+            id: player_id,
+            watched: world.group_to_watch.has_player(player_id),
+            position: (player.x, player.y),
+            trail
+        });
+    }
+    // highlighted riders drawn on top
+    riders.sort_by_key(|rider| (rider.watched, rider.id));
+
+    let (width, height) = (options.width as f64, options.height as f64);
+    let projection = Projection::fit(
+        riders.iter().flat_map(|rider| std::iter::once(&rider.position).chain(rider.trail.iter())),
+        width,
+        height
+    );
+
+    let mut svg = String::new();
+    let _ = writeln!(svg, r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#, options.width, options.height, options.width, options.height);
+    let _ = writeln!(svg, r#"<g class="trails" fill="none" stroke-width="2" stroke-opacity="0.5">"#);
+    for rider in riders.iter().filter(|rider| rider.trail.len() > 1) {
+        let points: Vec<String> = rider.trail.iter()
+            .map(|&point| {
+                let (x, y) = projection.project(point);
+                format!("{:.1},{:.1}", x, y)
+            })
+            .collect();
+        let color = if rider.watched { "#e4572e" } else { "#888888" };
+        let _ = writeln!(svg, r#"<polyline points="{}" stroke="{}"/>"#, points.join(" "), color);
+    }
+    let _ = writeln!(svg, "</g>");
+    let _ = writeln!(svg, r#"<g class="riders" font-family="sans-serif" font-size="12">"#);
+    for rider in riders.iter() {
+        let (x, y) = projection.project(rider.position);
+        let (radius, color) = match rider.watched {
+            true => (MAP_WATCH_DOT_RADIUS, "#e4572e"),
+            false => (MAP_DOT_RADIUS, "#888888")
+        };
+        let _ = writeln!(svg, r#"<circle id="rider-{}" cx="{:.1}" cy="{:.1}" r="{}" fill="{}"/>"#, rider.id, x, y, radius, color);
+        if options.labels && (rider.watched || options.watch_only) {
+            let label = world.profiles.name(rider.id)
+                .map(escape_xml)
+                .unwrap_or_else(|| rider.id.to_string());
+            let _ = writeln!(svg, r#"<text x="{:.1}" y="{:.1}">{}</text>"#, x + radius + 2., y + 4., label);
+        }
+    }
+    let _ = writeln!(svg, "</g>");
+    svg.push_str("</svg>\n");
+    svg
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::profiles::PlayerProfile;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn map_render_svg() {
+        let mut world = World::new();
+        let base_player = get_player_instance();
+        for (id, x, y) in [(1, 0., 0.), (2, 1000., 500.)].iter() {
+            let mut player = base_player.clone();
+            player.id = *id;
+            player.x = *x;
+            player.y = *y;
+            world.push_player(player);
+        }
+        world.add_player_to_watch(2);
+        world.profiles.set(2, PlayerProfile { name: Some(String::from("A <B>")), ..PlayerProfile::default() });
+
+        let svg = render_svg(&world, &MapOptions::default());
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(r#"<circle id="rider-1" cx="20.0" cy="490.0" r="4""#));
+        assert!(svg.contains(r#"<circle id="rider-2" cx="780.0" cy="110.0" r="6""#));
+        assert!(svg.contains("A &lt;B&gt;</text>"));
+
+        let svg = render_svg(&world, &MapOptions { watch_only: true, ..MapOptions::default() });
+        assert!(!svg.contains("rider-1"));
+        assert!(svg.contains(r#"cx="400.0" cy="300.0""#));
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use zwift_capture::Player;
+
+
+pub struct RiderTelemetry {
+    pub id: i32,
+    pub name: Option<String>,
+    pub weight: Option<f64>,
+    pub player: Player
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Prometheus gauges for every rider, labelled with player id and name
+pub fn render_riders(riders: &[RiderTelemetry]) -> String {
+    let mut output = String::new();
+    let gauges: [(&str, &str, fn(&RiderTelemetry) -> Option<f64>); 6] = [
+        ("zwift_rider_power_watts", "Rider power", |rider| Some(rider.player.power as f64)),
+        ("zwift_rider_heartrate_bpm", "Rider heart rate", |rider| Some(rider.player.heartrate as f64)),
+        ("zwift_rider_cadence_rpm", "Rider cadence", |rider| Some(rider.player.cadence as f64)),
+        ("zwift_rider_speed_meters_per_second", "Rider speed", |rider| Some(rider.player.speed as f64)),
+        ("zwift_rider_distance_meters", "Rider distance", |rider| Some(rider.player.distance as f64)),
+        ("zwift_rider_watts_per_kg", "Rider power to weight ratio", |rider| match rider.weight {
+            Some(weight) if weight > 0. => Some(rider.player.power as f64 / weight),
+            _ => None
+        })
+    ];
+    for (name, help, value) in gauges.iter() {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} gauge", name);
+        for rider in riders.iter() {
+            if let Some(value) = value(rider) {
+                let _ = writeln!(
+                    output,
+                    "{}{{id=\"{}\",name=\"{}\"}} {}",
+                    name,
+                    rider.id,
+                    escape_label(rider.name.as_deref().unwrap_or("")),
+                    value
+                );
+            }
+        }
+    }
+    output
+}
+
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub packets_processed: AtomicU64,
+    pub players_decoded: AtomicU64,
+    pub active_players: AtomicU64,
+    pub evicted_players: AtomicU64,
+    pub lock_wait_micros: AtomicU64,
+    pub capture_errors: AtomicU64,
+    pub device_reopens: AtomicU64,
+    pub influx_dropped: AtomicU64,
+    // batches per second * 1000, updated on every tick
+    batches_rate: AtomicU64,
+    last_tick: Mutex<(Instant, u64)>
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            packets_processed: AtomicU64::new(0),
+            players_decoded: AtomicU64::new(0),
+            active_players: AtomicU64::new(0),
+            evicted_players: AtomicU64::new(0),
+            lock_wait_micros: AtomicU64::new(0),
+            capture_errors: AtomicU64::new(0),
+            device_reopens: AtomicU64::new(0),
+            influx_dropped: AtomicU64::new(0),
+            batches_rate: AtomicU64::new(0),
+            last_tick: Mutex::new((Instant::now(), 0))
+        }
+    }
+
+    pub fn record_batch(&self, players: usize, lock_wait: Duration) {
+        self.packets_processed.fetch_add(1, Ordering::Relaxed);
+        self.players_decoded.fetch_add(players as u64, Ordering::Relaxed);
+        self.lock_wait_micros.fetch_add(lock_wait.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn set_active_players(&self, count: usize) {
+        self.active_players.store(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_evicted(&self, count: usize) {
+        self.evicted_players.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_capture_error(&self) {
+        self.capture_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_device_reopen(&self) {
+        self.device_reopens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_influx_dropped(&self, count: usize) {
+        self.influx_dropped.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn tick(&self) {
+        let packets = self.packets_processed.load(Ordering::Relaxed);
+        let mut last_tick = self.last_tick.lock().unwrap();
+        let elapsed = last_tick.0.elapsed().as_secs_f64();
+        if elapsed > 0. {
+            let rate = (packets - last_tick.1) as f64 / elapsed;
+            self.batches_rate.store((rate * 1000.) as u64, Ordering::Relaxed);
+        }
+        *last_tick = (Instant::now(), packets);
+    }
+
+    pub fn batches_per_second(&self) -> f64 {
+        self.batches_rate.load(Ordering::Relaxed) as f64 / 1000.
+    }
+
+    fn write_metric(output: &mut String, name: &str, kind: &str, help: &str, value: f64) {
+        let _ = writeln!(output, "# HELP {} {}", name, help);
+        let _ = writeln!(output, "# TYPE {} {}", name, kind);
+        let _ = writeln!(output, "{} {}", name, value);
+    }
+
+    /// Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        let counter = |metric: &AtomicU64| metric.load(Ordering::Relaxed) as f64;
+        Metrics::write_metric(&mut output, "zwift_watcher_packets_processed_total", "counter",
+            "Captured packets pushed to world", counter(&self.packets_processed));
+        Metrics::write_metric(&mut output, "zwift_watcher_players_decoded_total", "counter",
+            "Decoded player records", counter(&self.players_decoded));
+        Metrics::write_metric(&mut output, "zwift_watcher_batches_per_second", "gauge",
+            "Packets per second over last tick", self.batches_per_second());
+        Metrics::write_metric(&mut output, "zwift_watcher_active_players", "gauge",
+            "Players currently in world", counter(&self.active_players));
+        Metrics::write_metric(&mut output, "zwift_watcher_evicted_players_total", "counter",
+            "Outdated players removed from world", counter(&self.evicted_players));
+        Metrics::write_metric(&mut output, "zwift_watcher_lock_wait_seconds_total", "counter",
+            "Time capture thread waited for world lock", counter(&self.lock_wait_micros) / 1_000_000.);
+        Metrics::write_metric(&mut output, "zwift_watcher_capture_errors_total", "counter",
+            "Capture stopped with device failure", counter(&self.capture_errors));
+        Metrics::write_metric(&mut output, "zwift_watcher_device_reopen_total", "counter",
+            "Capture device reopened", counter(&self.device_reopens));
+        Metrics::write_metric(&mut output, "zwift_watcher_influx_dropped_points_total", "counter",
+            "Player updates dropped while InfluxDB writer was behind", counter(&self.influx_dropped));
+        output
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn metrics_render() {
+        let metrics = Metrics::new();
+        metrics.record_batch(3, Duration::from_millis(500));
+        metrics.record_batch(2, Duration::from_millis(500));
+        metrics.record_evicted(4);
+        metrics.set_active_players(7);
+        metrics.record_influx_dropped(12);
+        let output = metrics.render();
+        assert!(output.contains("# TYPE zwift_watcher_packets_processed_total counter\n"));
+        assert!(output.contains("zwift_watcher_packets_processed_total 2\n"));
+        assert!(output.contains("zwift_watcher_players_decoded_total 5\n"));
+        assert!(output.contains("zwift_watcher_active_players 7\n"));
+        assert!(output.contains("zwift_watcher_evicted_players_total 4\n"));
+        assert!(output.contains("zwift_watcher_lock_wait_seconds_total 1\n"));
+        assert!(output.contains("zwift_watcher_influx_dropped_points_total 12\n"));
+    }
+
+    #[test]
+    fn metrics_render_riders() {
+        let player = get_player_instance();
+        let riders = vec![
+            RiderTelemetry { id: player.id, name: Some(String::from("Rider \"One\"")), weight: Some(50.), player: player.clone() },
+            RiderTelemetry { id: player.id + 1, name: None, weight: None, player: player.clone() }
+        ];
+        let output = render_riders(&riders);
+        assert!(output.contains(&format!("zwift_rider_power_watts{{id=\"{}\",name=\"Rider \\\"One\\\"\"}} {}\n", player.id, player.power)));
+        assert!(output.contains(&format!("zwift_rider_heartrate_bpm{{id=\"{}\",name=\"\"}} {}\n", player.id + 1, player.heartrate)));
+        assert_eq!(output.matches("zwift_rider_watts_per_kg{").count(), 1);
+    }
+}
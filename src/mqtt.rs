@@ -0,0 +1,245 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use crate::World;
+
+
+#[derive(Debug,Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub qos: u8,
+    pub retain: bool,
+    /// subscribe to command topic, anyone allowed to publish on broker changes watch group
+    pub commands: bool,
+    pub topic_prefix: String,
+    pub publish_interval: Duration
+}
+
+impl MqttConfig {
+    pub fn new(host: &str, port: u16) -> Self {
+        MqttConfig {
+            host: String::from(host),
+            port,
+            client_id: String::from("zwift_watcher"),
+            qos: 0,
+            retain: false,
+            commands: false,
+            topic_prefix: String::from("zwift"),
+            publish_interval: Duration::from_secs(1)
+        }
+    }
+
+    pub fn player_topic(&self, player_id: i32) -> String {
+        format!("{}/players/{}", self.topic_prefix, player_id)
+    }
+
+    pub fn watch_topic(&self) -> String {
+        format!("{}/watch", self.topic_prefix)
+    }
+
+    pub fn attacks_topic(&self) -> String {
+        format!("{}/events/attacks", self.topic_prefix)
+    }
+
+    pub fn command_topic(&self) -> String {
+        format!("{}/watch/command", self.topic_prefix)
+    }
+
+    fn qos(&self) -> QoS {
+        match self.qos {
+            2 => QoS::ExactlyOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::AtMostOnce
+        }
+    }
+}
+
+
+/// Watch group changes received on command topic,
+/// e.g. `{"action": "add", "id": 108934}` or `{"action": "clear"}`
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum WatchCommand {
+    Add { id: i32 },
+    Remove { id: i32 },
+    Clear
+}
+
+impl WatchCommand {
+    pub fn parse(payload: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(payload).map_err(|err| err.to_string())
+    }
+
+    pub fn apply(&self, world: &mut World) {
+        match self {
+            WatchCommand::Add { id } => world.add_player_to_watch(*id),
+            WatchCommand::Remove { id } => world.remove_player_from_watch(*id),
+            WatchCommand::Clear => world.clear_group_to_watch()
+        }
+    }
+}
+
+
+/// Messages for every watched rider and synchronized watch group snapshot
+pub fn build_messages(config: &MqttConfig, world: &World) -> Vec<(String, Vec<u8>)> {
+    let mut messages = Vec::new();
+    for player in world.get_group_to_watch_data(true).iter() {
+        if let Ok(payload) = serde_json::to_vec(player) {
+            messages.push((config.player_topic(player.id), payload));
+        }
+    }
+    let snapshot = serde_json::json!({
+        "world_time": world.get_latest_world_time_for_group(&world.group_to_watch),
+        "data": world.get_group_to_watch_data(false)
+    });
+    if let Ok(payload) = serde_json::to_vec(&snapshot) {
+        messages.push((config.watch_topic(), payload));
+    }
+    messages
+}
+
+/// Attack events detected after `last_event_id`
+pub fn build_event_messages(config: &MqttConfig, world: &World, last_event_id: &mut u64) -> Vec<(String, Vec<u8>)> {
+    let mut messages = Vec::new();
+    for event in world.attacks.events_since(*last_event_id).iter() {
+        *last_event_id = event.id;
+        if let Ok(payload) = serde_json::to_vec(event) {
+            messages.push((config.attacks_topic(), payload));
+        }
+    }
+    messages
+}
+
+async fn publish_loop(config: MqttConfig, client: AsyncClient, world: Arc<Mutex<World>>) {
+    let mut interval = tokio::time::interval(config.publish_interval);
+    let mut last_event_id = 0;
+    loop {
+        interval.tick().await;
+        let messages = {
+            let world = world.lock().unwrap();
+            let mut messages = build_messages(&config, &world);
+            messages.extend(build_event_messages(&config, &world, &mut last_event_id));
+            messages
+        };
+        for (topic, payload) in messages.into_iter() {
+            if let Err(err) = client.publish(topic, config.qos(), config.retain, payload).await {
+                println!("MQTT publish error: {}", err);
+            }
+        }
+    }
+}
+
+pub async fn run(config: MqttConfig, world: Arc<Mutex<World>>) {
+    let mut options = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    let (client, mut eventloop) = AsyncClient::new(options, 100);
+    let command_topic = config.command_topic();
+
+    tokio::spawn(publish_loop(config.clone(), client.clone(), world.clone()));
+
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                println!("MQTT connected: {}:{}", config.host, config.port);
+                if !config.commands {
+                    continue;
+                }
+                if let Err(err) = client.subscribe(command_topic.clone(), config.qos()).await {
+                    println!("MQTT subscribe error: {}", err);
+                }
+            },
+            Ok(Event::Incoming(Packet::Publish(publish))) if config.commands && publish.topic == command_topic => {
+                match WatchCommand::parse(&publish.payload) {
+                    Ok(command) => command.apply(&mut world.lock().unwrap()),
+                    Err(err) => println!("MQTT invalid command: {}", err)
+                }
+            },
+            Ok(_) => {},
+            Err(err) => {
+                println!("MQTT connection error: {}", err);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn mqtt_watch_command() {
+        let mut world = World::new();
+        WatchCommand::parse(br#"{"action": "add", "id": 1}"#).unwrap().apply(&mut world);
+        WatchCommand::parse(br#"{"action": "add", "id": 2}"#).unwrap().apply(&mut world);
+        assert!(world.group_to_watch.has_player(1));
+        WatchCommand::parse(br#"{"action": "remove", "id": 1}"#).unwrap().apply(&mut world);
+        assert!(!world.group_to_watch.has_player(1));
+        assert!(world.group_to_watch.has_player(2));
+        WatchCommand::parse(br#"{"action": "clear"}"#).unwrap().apply(&mut world);
+        assert!(!world.group_to_watch.has_player(2));
+        assert!(WatchCommand::parse(br#"{"action": "add"}"#).is_err());
+    }
+
+    #[test]
+    fn mqtt_build_messages() {
+        let config = MqttConfig::new("localhost", 1883);
+        let mut world = World::new();
+        let player = get_player_instance();
+        let player_id = player.id;
+        world.push_player(player);
+        world.add_player_to_watch(player_id);
+        let messages = build_messages(&config, &world);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].0, format!("zwift/players/{}", player_id));
+        assert_eq!(messages[1].0, "zwift/watch");
+        let snapshot: serde_json::Value = serde_json::from_slice(&messages[1].1).unwrap();
+        assert_eq!(snapshot["data"][0]["id"], player_id);
+    }
+
+    /// needs local broker, e.g. `mosquitto -p 1883`
+    #[tokio::test]
+    #[ignore]
+    async fn mqtt_broker_publish_and_command() {
+        let mut config = MqttConfig::new("localhost", 1883);
+        config.client_id = format!("zwift_watcher_test_{}", std::process::id());
+        config.topic_prefix = format!("zwift_test_{}", std::process::id());
+        config.publish_interval = Duration::from_millis(100);
+        config.commands = true;
+        let player = get_player_instance();
+        let player_id = player.id;
+        let mut world = World::new();
+        world.push_player(player);
+        world.add_player_to_watch(player_id);
+        let world = Arc::new(Mutex::new(world));
+        tokio::spawn(run(config.clone(), world.clone()));
+
+        let options = MqttOptions::new(format!("{}_subscriber", config.client_id), "localhost", 1883);
+        let (client, mut eventloop) = AsyncClient::new(options, 10);
+        client.subscribe(config.player_topic(player_id), QoS::AtLeastOnce).await.unwrap();
+        let payload = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Event::Incoming(Packet::Publish(publish)) = eventloop.poll().await.unwrap() {
+                    return publish.payload;
+                }
+            }
+        }).await.unwrap();
+        let data: serde_json::Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(data["id"], player_id);
+
+        let cleared = tokio::time::timeout(Duration::from_secs(5), async {
+            while world.lock().unwrap().group_to_watch.has_player(player_id) {
+                // repeated until watcher subscribed to command topic
+                client.publish(config.command_topic(), QoS::AtLeastOnce, false, r#"{"action": "clear"}"#).await.unwrap();
+                let _ = tokio::time::timeout(Duration::from_millis(100), eventloop.poll()).await;
+            }
+        }).await;
+        assert!(cleared.is_ok());
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use serde_json::Value;
+
+
+const OVERLAY_TEMPLATE_EXTENSION: &str = "html";
+// transparent background for OBS browser source, body re-rendered on every watch group stream event,
+// query is passed to the stream as it may carry access token
+const OVERLAY_LIVE_HEAD: &str = r#"<style>html, body { background: transparent; }</style>
+<script>
+(function () {
+    var loading = false;
+    var events = new EventSource('/watch/stream' + window.location.search);
+    events.addEventListener('watch', function () {
+        if (loading) {
+            return;
+        }
+        loading = true;
+        fetch(window.location.href)
+            .then(function (response) { return response.ok ? response.text() : null; })
+            .then(function (page) {
+                if (page !== null) {
+                    document.body.innerHTML = new DOMParser().parseFromString(page, 'text/html').body.innerHTML;
+                }
+            })
+            .catch(function () {})
+            .then(function () { loading = false; });
+    });
+})();
+</script>
+"#;
+
+fn escape_html(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(value) => escape_html(value),
+        Value::Number(number) => match (number.as_i64(), number.as_f64()) {
+            (Some(number), _) => number.to_string(),
+            (None, Some(number)) => format!("{:.1}", number),
+            _ => number.to_string()
+        },
+        value => escape_html(&value.to_string())
+    }
+}
+
+/// Dotted path like `profile.name`, in the innermost context first
+fn lookup<'a>(path: &str, stack: &[&'a Value]) -> Option<&'a Value> {
+    for context in stack.iter().rev() {
+        let mut current = Some(*context);
+        for key in path.split('.') {
+            current = current.and_then(|value| value.get(key));
+        }
+        match current {
+            Some(Value::Null) | None => continue,
+            found => return found
+        }
+    }
+    None
+}
+
+fn render_with(template: &str, stack: &mut Vec<&Value>, output: &mut String) {
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            _ => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let tag = rest[start + 2..end].trim();
+        rest = &rest[end + 2..];
+
+        if let Some(name) = tag.strip_prefix('#') {
+            let closing = format!("{{{{/{}}}}}", name);
+            let (inner, after) = match rest.find(&closing) {
+                Some(position) => (&rest[..position], &rest[position + closing.len()..]),
+                _ => (rest, "")
+            };
+            rest = after;
+            match lookup(name, stack) {
+                Some(Value::Array(items)) => {
+                    for item in items.iter() {
+                        stack.push(item);
+                        render_with(inner, stack, output);
+                        stack.pop();
+                    }
+                },
+                Some(Value::Bool(false)) | None => {},
+                Some(value) => {
+                    stack.push(value);
+                    render_with(inner, stack, output);
+                    stack.pop();
+                }
+            }
+        } else if let Some(value) = lookup(tag, stack) {
+            output.push_str(&format_value(value));
+        }
+    }
+    output.push_str(rest);
+}
+
+/// Fill `{{path}}` values and repeat `{{#riders}}...{{/riders}}` sections, values are HTML-escaped
+pub fn render(template: &str, context: &Value) -> String {
+    let mut output = String::with_capacity(template.len());
+    render_with(template, &mut vec![context], &mut output);
+    output
+}
+
+/// Rendered page with transparent background, updated live from watch group stream
+pub fn live_page(page: &str) -> String {
+    let mut output = String::with_capacity(page.len() + OVERLAY_LIVE_HEAD.len());
+    match page.find("</head>").or_else(|| page.find("</HEAD>")) {
+        Some(position) => {
+            output.push_str(&page[..position]);
+            output.push_str(OVERLAY_LIVE_HEAD);
+            output.push_str(&page[position..]);
+        },
+        _ => {
+            output.push_str(OVERLAY_LIVE_HEAD);
+            output.push_str(page);
+        }
+    }
+    output
+}
+
+
+/// Templates from directory, modification time and size of the file are checked on every request
+/// and template is re-read when they change, directory is not watched
+#[derive(Debug)]
+pub struct OverlayTemplates {
+    dir: PathBuf,
+    cache: Mutex<HashMap<String, ((SystemTime, u64), String)>>
+}
+
+impl OverlayTemplates {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        OverlayTemplates {
+            dir: dir.into(),
+            cache: Mutex::new(HashMap::new())
+        }
+    }
+
+    fn valid_name(name: &str) -> bool {
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        let mut result: Vec<String> = fs::read_dir(&self.dir)
+            .map(|entries| entries.filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().map(|ext| ext == OVERLAY_TEMPLATE_EXTENSION).unwrap_or(false))
+                .filter_map(|path| path.file_stem().and_then(|stem| stem.to_str()).map(String::from))
+                .filter(|name| OverlayTemplates::valid_name(name))
+                .collect())
+            .unwrap_or_default();
+        result.sort();
+        result
+    }
+
+    /// Template source by name, without extension
+    pub fn get(&self, name: &str) -> Result<String, String> {
+        if !OverlayTemplates::valid_name(name) {
+            return Err(format!("Invalid template name: {}", name));
+        }
+        let path = self.dir.join(format!("{}.{}", name, OVERLAY_TEMPLATE_EXTENSION));
+        let metadata = fs::metadata(&path).map_err(|err| err.to_string())?;
+        let version = (metadata.modified().map_err(|err| err.to_string())?, metadata.len());
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((cached_version, source)) = cache.get(name) {
+            if *cached_version == version {
+                return Ok(source.clone());
+            }
+        }
+        let source = fs::read_to_string(&path).map_err(|err| err.to_string())?;
+        cache.insert(String::from(name), (version, source.clone()));
+        Ok(source)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn overlay_render() {
+        let context = serde_json::json!({
+            "world_time": 1000,
+            "riders": [
+                {"id": 1, "name": "A & B", "power": 250, "speed": 10.26, "profile": {"team": "T"}},
+                {"id": 2, "name": "C", "power": 300, "speed": 9.5, "profile": null}
+            ]
+        });
+        let template = "<p>{{world_time}}</p>{{#riders}}<div>{{name}} {{power}}W {{speed}} {{profile.team}}</div>{{/riders}}{{unknown}}";
+        assert_eq!(
+            render(template, &context),
+            "<p>1000</p><div>A &amp; B 250W 10.3 T</div><div>C 300W 9.5 </div>"
+        );
+    }
+
+    #[test]
+    fn overlay_live_page() {
+        let page = live_page("<html><head><title>T</title></head><body>1</body></html>");
+        assert!(page.starts_with("<html><head><title>T</title><style>html, body { background: transparent; }</style>"));
+        assert!(page.contains("new EventSource('/watch/stream'"));
+        assert!(page.ends_with("</script>\n</head><body>1</body></html>"));
+        let fragment = live_page("<div>1</div>");
+        assert!(fragment.starts_with("<style>"));
+        assert!(fragment.ends_with("<div>1</div>"));
+    }
+
+    #[test]
+    fn overlay_templates_reload() {
+        let dir = std::env::temp_dir().join(format!("zwift_watcher_overlay_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let templates = OverlayTemplates::new(&dir);
+        fs::write(dir.join("riders.html"), "one").unwrap();
+        assert_eq!(templates.get("riders").unwrap(), "one");
+        fs::write(dir.join("riders.html"), "second").unwrap();
+        assert_eq!(templates.get("riders").unwrap(), "second");
+        assert_eq!(templates.list(), vec![String::from("riders")]);
+        assert!(templates.get("../riders").is_err());
+        assert!(templates.get("missing").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
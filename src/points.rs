@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use crate::segments::{SegmentKind, SegmentTracker};
+use crate::race::RaceTracker;
+use crate::profiles::ProfileRegistry;
+use crate::teams::TeamConfig;
+
+
+/// Points by place, first value for the winner
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct PointsConfig {
+    #[serde(default = "PointsConfig::default_sprint")]
+    pub sprint: Vec<i32>,
+    #[serde(default = "PointsConfig::default_finish")]
+    pub finish: Vec<i32>,
+    /// scale for particular segment id instead of sprint scale, e.g. for KOMs
+    #[serde(default)]
+    pub segments: HashMap<usize, Vec<i32>>
+}
+
+impl PointsConfig {
+    fn default_sprint() -> Vec<i32> {
+        vec![5, 3, 2, 1]
+    }
+
+    fn default_finish() -> Vec<i32> {
+        vec![10, 7, 5, 3, 2, 1]
+    }
+
+    fn points(scale: &[i32], place: usize) -> i32 {
+        scale.get(place - 1).cloned().unwrap_or(0)
+    }
+}
+
+impl Default for PointsConfig {
+    fn default() -> Self {
+        PointsConfig {
+            sprint: PointsConfig::default_sprint(),
+            finish: PointsConfig::default_finish(),
+            segments: HashMap::new()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct PointsAward {
+    pub player_id: i32,
+    /// segment id, none for race finish
+    pub segment_id: Option<usize>,
+    pub name: String,
+    /// segment passes are counted separately, e.g. sprint on every lap
+    pub pass: usize,
+    pub place: usize,
+    pub points: i32,
+    pub world_time: i64
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct RiderPoints {
+    pub position: usize,
+    pub player_id: i32,
+    pub name: Option<String>,
+    pub team: Option<String>,
+    pub points: i32
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct TeamPoints {
+    pub position: usize,
+    pub team: String,
+    pub points: i32,
+    pub riders: Vec<i32>
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq, Default)]
+pub struct PointsStandings {
+    pub riders: Vec<RiderPoints>,
+    pub teams: Vec<TeamPoints>,
+    pub history: Vec<PointsAward>
+}
+
+/// Points for every scored segment pass and race finish, oldest first
+pub fn awards(config: &PointsConfig, segments: &SegmentTracker, race: &RaceTracker, profiles: &ProfileRegistry) -> Vec<PointsAward> {
+    let mut result = Vec::new();
+
+    // nth crossing of every rider belongs to nth pass of the segment
+    let mut passes: HashMap<(usize, usize), Vec<(i64, i32)>> = HashMap::new();
+    let mut rider_passes: HashMap<(usize, i32), usize> = HashMap::new();
+    let mut efforts: Vec<_> = segments.all_efforts().iter().collect();
+    efforts.sort_by_key(|effort| effort.end_time);
+    for effort in efforts.into_iter() {
+        let pass = rider_passes.entry((effort.segment_id, effort.player_id)).or_insert(0);
+        *pass += 1;
+        passes.entry((effort.segment_id, *pass)).or_insert_with(Vec::new).push((effort.end_time, effort.player_id));
+    }
+    for ((segment_id, pass), crossings) in passes.into_iter() {
+        let segment = match segments.get_segment(segment_id) {
+            Some(segment) => segment,
+            _ => continue
+        };
+        let scale = match (config.segments.get(&segment_id), segment.kind) {
+            (Some(scale), _) => scale,
+            (None, SegmentKind::Sprint) => &config.sprint,
+            _ => continue
+        };
+        for (ix, (world_time, player_id)) in crossings.into_iter().enumerate() {
+            let points = PointsConfig::points(scale, ix + 1);
+            if points == 0 {
+                break;
+            }
+            result.push(PointsAward {
+                player_id,
+                segment_id: Some(segment_id),
+                name: segment.name.clone(),
+                pass,
+                place: ix + 1,
+                points,
+                world_time
+            });
+        }
+    }
+
+    for race_result in race.results(profiles).into_iter() {
+        let finished_at = match race_result.finished_at {
+            Some(finished_at) => finished_at,
+            _ => break
+        };
+        let points = PointsConfig::points(&config.finish, race_result.position);
+        if points == 0 {
+            break;
+        }
+        result.push(PointsAward {
+            player_id: race_result.id,
+            segment_id: None,
+            name: String::from("Finish"),
+            pass: 1,
+            place: race_result.position,
+            points,
+            world_time: finished_at
+        });
+    }
+
+    result.sort_by_key(|award| (award.world_time, award.place));
+    result
+}
+
+/// Running standings of riders and teams
+pub fn standings(config: &PointsConfig, segments: &SegmentTracker, race: &RaceTracker, teams: &TeamConfig, profiles: &ProfileRegistry) -> PointsStandings {
+    let history = awards(config, segments, race, profiles);
+
+    let mut by_rider: HashMap<i32, i32> = HashMap::new();
+    for award in history.iter() {
+        *by_rider.entry(award.player_id).or_insert(0) += award.points;
+    }
+    let mut riders: Vec<(i32, i32)> = by_rider.into_iter().collect();
+    riders.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let mut by_team: HashMap<String, (i32, Vec<i32>)> = HashMap::new();
+    for &(player_id, points) in riders.iter() {
+        if let Some(team) = teams.team_of(player_id, profiles) {
+            let team = by_team.entry(team).or_insert((0, Vec::new()));
+            team.0 += points;
+            team.1.push(player_id);
+        }
+    }
+    let mut team_points: Vec<(String, (i32, Vec<i32>))> = by_team.into_iter().collect();
+    team_points.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then(a.0.cmp(&b.0)));
+
+    PointsStandings {
+        riders: riders.into_iter()
+            .enumerate()
+            .map(|(ix, (player_id, points))| RiderPoints {
+                position: ix + 1,
+                player_id,
+                name: profiles.name(player_id).map(String::from),
+                team: teams.team_of(player_id, profiles),
+                points
+            })
+            .collect(),
+        teams: team_points.into_iter()
+            .enumerate()
+            .map(|(ix, (team, (points, riders)))| TeamPoints {
+                position: ix + 1,
+                team,
+                points,
+                riders
+            })
+            .collect(),
+        history
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::World;
+    use crate::profiles::PlayerProfile;
+    use crate::race::Gate;
+    use crate::segments::{Segment, SegmentBoundary};
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn points_standings() {
+        let mut world = World::new();
+        world.segments.add_segment(Segment {
+            id: 0,
+            name: String::from("Sprint"),
+            kind: SegmentKind::Sprint,
+            world_id: None,
+            start: SegmentBoundary::Gate { gate: Gate { x1: 100., y1: -10., x2: 100., y2: 10. } },
+            end: SegmentBoundary::Gate { gate: Gate { x1: 200., y1: -10., x2: 200., y2: 10. } }
+        });
+        for (id, team) in [(1, "A"), (2, "B"), (3, "A")].iter() {
+            world.profiles.set(*id, PlayerProfile { team: Some(String::from(*team)), ..PlayerProfile::default() });
+        }
+        let base_player = get_player_instance();
+        for step in 0..30 {
+            for (id, speed) in [(1, 10.), (2, 12.), (3, 11.)].iter() {
+                let mut player = base_player.clone();
+                player.id = *id;
+                player.world_time = base_player.world_time + step * 1000;
+                player.x = step as f64 * speed;
+                player.y = 0.;
+                world.push_player(player);
+            }
+            if step == 0 {
+                for id in 1..4 {
+                    world.add_player_to_watch(id);
+                }
+            }
+            world.push_players_batch(Vec::new());
+        }
+        let config = PointsConfig::default();
+        let standings = standings(&config, &world.segments, &world.race, &world.teams, &world.profiles);
+        let riders: Vec<(i32, i32)> = standings.riders.iter().map(|rider| (rider.player_id, rider.points)).collect();
+        assert_eq!(riders, vec![(2, 5), (3, 3), (1, 2)]);
+        let teams: Vec<(&str, i32)> = standings.teams.iter().map(|team| (team.team.as_str(), team.points)).collect();
+        assert_eq!(teams, vec![("A", 5), ("B", 5)]);
+        assert_eq!(standings.history.len(), 3);
+        assert_eq!(standings.history[0].player_id, 2);
+        assert_eq!(standings.history[0].place, 1);
+    }
+}
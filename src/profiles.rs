@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use zwift_capture::Player;
+use crate::server::openapi::PlayerSchema;
+
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Default, PartialEq)]
+pub struct PlayerProfile {
+    pub name: Option<String>,
+    pub team: Option<String>,
+    /// kg
+    pub weight: Option<f64>,
+    pub ftp: Option<i32>,
+    pub max_heartrate: Option<i32>,
+    pub country: Option<String>
+}
+
+impl PlayerProfile {
+    /// Copy known fields from other profile, keep current values for unknown ones
+    pub fn merge(&mut self, other: PlayerProfile) {
+        if other.name.is_some() { self.name = other.name }
+        if other.team.is_some() { self.team = other.team }
+        if other.weight.is_some() { self.weight = other.weight }
+        if other.ftp.is_some() { self.ftp = other.ftp }
+        if other.max_heartrate.is_some() { self.max_heartrate = other.max_heartrate }
+        if other.country.is_some() { self.country = other.country }
+    }
+
+    /// Copy fields unknown in current profile, values entered manually take precedence
+    pub fn fill_missing(&mut self, other: &PlayerProfile) -> bool {
+        let mut changed = false;
+        if self.name.is_none() && other.name.is_some() { self.name = other.name.clone(); changed = true }
+        if self.team.is_none() && other.team.is_some() { self.team = other.team.clone(); changed = true }
+        if self.weight.is_none() && other.weight.is_some() { self.weight = other.weight; changed = true }
+        if self.ftp.is_none() && other.ftp.is_some() { self.ftp = other.ftp; changed = true }
+        if self.max_heartrate.is_none() && other.max_heartrate.is_some() { self.max_heartrate = other.max_heartrate; changed = true }
+        if self.country.is_none() && other.country.is_some() { self.country = other.country.clone(); changed = true }
+        changed
+    }
+
+    pub fn watts_per_kg(&self, power: f64) -> Option<f64> {
+        match self.weight {
+            Some(weight) if weight > 0. => Some(power / weight),
+            _ => None
+        }
+    }
+}
+
+
+#[derive(Debug,Default)]
+pub struct ProfileRegistry {
+    profiles: HashMap<i32, PlayerProfile>,
+    // file to save changes, if loaded from file
+    path: Option<PathBuf>,
+    // profiles changed since last `take_unsaved`
+    unsaved: bool
+}
+
+impl ProfileRegistry {
+    pub fn new() -> Self {
+        ProfileRegistry::default()
+    }
+
+    /// Load profiles from JSON file `{"<player id>": {"name": ..., "weight": ...}}`,
+    /// empty registry if file doesn't exist yet
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let profiles = match path.exists() {
+            true => {
+                let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+                serde_json::from_str(&content).map_err(|err| err.to_string())?
+            },
+            false => HashMap::new()
+        };
+        Ok(ProfileRegistry {
+            profiles,
+            path: Some(path.to_path_buf()),
+            unsaved: false
+        })
+    }
+
+    pub fn save(&self) -> Result<(), String> {
+        if let Some(path) = &self.path {
+            let content = serde_json::to_string_pretty(&self.profiles).map_err(|err| err.to_string())?;
+            fs::write(path, content).map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, player_id: i32) -> Option<&PlayerProfile> {
+        self.profiles.get(&player_id)
+    }
+
+    pub fn set(&mut self, player_id: i32, profile: PlayerProfile) {
+        self.profiles.insert(player_id, profile);
+    }
+
+    pub fn update(&mut self, player_id: i32, profile: PlayerProfile) -> &PlayerProfile {
+        self.unsaved = true;
+        let current = self.profiles.entry(player_id).or_insert_with(PlayerProfile::default);
+        current.merge(profile);
+        current
+    }
+
+    /// Profile decoded from captured messages, doesn't override known values
+    pub fn push_decoded(&mut self, player_id: i32, profile: &PlayerProfile) -> bool {
+        if *profile == PlayerProfile::default() {
+            return false;
+        }
+        let changed = self.profiles.entry(player_id).or_insert_with(PlayerProfile::default).fill_missing(profile);
+        self.unsaved |= changed;
+        changed
+    }
+
+    /// Copy of registry if profiles changed since last call, to save it without holding world lock
+    pub fn take_unsaved(&mut self) -> Option<ProfileRegistry> {
+        if !self.unsaved {
+            return None;
+        }
+        self.unsaved = false;
+        Some(ProfileRegistry {
+            profiles: self.profiles.clone(),
+            path: self.path.clone(),
+            unsaved: false
+        })
+    }
+
+    pub fn remove(&mut self, player_id: i32) -> Option<PlayerProfile> {
+        let profile = self.profiles.remove(&player_id);
+        self.unsaved |= profile.is_some();
+        profile
+    }
+
+    pub fn all(&self) -> &HashMap<i32, PlayerProfile> {
+        &self.profiles
+    }
+
+    pub fn name(&self, player_id: i32) -> Option<&str> {
+        self.get(player_id).and_then(|profile| profile.name.as_deref())
+    }
+
+    pub fn watts_per_kg(&self, player: &Player) -> Option<f64> {
+        self.get(player.id).and_then(|profile| profile.watts_per_kg(player.power as f64))
+    }
+
+    /// Player data with profile and power to weight ratio
+    pub fn join(&self, player: &Player) -> PlayerRecord {
+        PlayerRecord {
+            player: player.clone(),
+            profile: self.get(player.id).cloned(),
+            wkg: self.watts_per_kg(player)
+        }
+    }
+
+    /// Stored player sample with profile and power to weight ratio, same fields as `join`
+    pub fn join_value(&self, player_id: i32, mut data: serde_json::Value) -> serde_json::Value {
+        let profile = self.get(player_id);
+        let wkg = match (profile, data["power"].as_f64()) {
+            (Some(profile), Some(power)) => profile.watts_per_kg(power),
+            _ => None
+        };
+        if let Some(fields) = data.as_object_mut() {
+            fields.insert(String::from("profile"), serde_json::json!(profile));
+            fields.insert(String::from("wkg"), serde_json::json!(wkg));
+        }
+        data
+    }
+}
+
+/// Player fields with `profile` and `wkg`
+#[derive(Debug, Serialize, JsonSchema, Clone)]
+pub struct PlayerRecord {
+    #[serde(flatten)]
+    #[schemars(with = "PlayerSchema")]
+    pub player: Player,
+    pub profile: Option<PlayerProfile>,
+    /// W/kg, when weight is known
+    pub wkg: Option<f64>
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn profile_merge() {
+        let mut registry = ProfileRegistry::new();
+        registry.set(1, PlayerProfile { name: Some(String::from("Rider")), weight: Some(70.), ..PlayerProfile::default() });
+        let profile = registry.update(1, PlayerProfile { team: Some(String::from("TEAM")), weight: Some(72.), ..PlayerProfile::default() });
+        assert_eq!(profile.name.as_deref(), Some("Rider"));
+        assert_eq!(profile.team.as_deref(), Some("TEAM"));
+        assert_eq!(profile.weight, Some(72.));
+        assert!(registry.take_unsaved().is_some());
+        assert!(registry.remove(2).is_none());
+        assert!(registry.take_unsaved().is_none());
+        assert!(registry.remove(1).is_some());
+        assert!(registry.take_unsaved().unwrap().get(1).is_none());
+    }
+
+    #[test]
+    fn profile_push_decoded() {
+        let mut registry = ProfileRegistry::new();
+        registry.set(1, PlayerProfile { name: Some(String::from("Manual")), ..PlayerProfile::default() });
+        let decoded = PlayerProfile { name: Some(String::from("Decoded")), weight: Some(68.), ..PlayerProfile::default() };
+        assert!(registry.push_decoded(1, &decoded));
+        assert!(!registry.push_decoded(1, &decoded));
+        assert_eq!(registry.name(1), Some("Manual"));
+        assert_eq!(registry.get(1).unwrap().weight, Some(68.));
+        assert!(registry.push_decoded(2, &decoded));
+        assert_eq!(registry.name(2), Some("Decoded"));
+        assert!(!registry.push_decoded(3, &PlayerProfile::default()));
+        assert!(registry.get(3).is_none());
+        assert_eq!(registry.take_unsaved().unwrap().name(2), Some("Decoded"));
+        assert!(registry.take_unsaved().is_none());
+    }
+
+    #[test]
+    fn profile_join() {
+        let mut registry = ProfileRegistry::new();
+        let mut player = get_player_instance();
+        player.power = 200 as _;
+        assert!(serde_json::to_value(registry.join(&player)).unwrap()["profile"].is_null());
+        registry.set(player.id, PlayerProfile { name: Some(String::from("Rider")), weight: Some(80.), ..PlayerProfile::default() });
+        let value = serde_json::to_value(registry.join(&player)).unwrap();
+        assert_eq!(value["id"], player.id);
+        assert_eq!(value["profile"]["name"], "Rider");
+        assert_eq!(value["wkg"], 2.5);
+    }
+
+    #[test]
+    fn profile_file() {
+        let path = std::env::temp_dir().join(format!("zwift_watcher_profiles_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut registry = ProfileRegistry::from_file(&path).unwrap();
+        registry.set(108934, PlayerProfile { name: Some(String::from("Rider")), ..PlayerProfile::default() });
+        registry.save().unwrap();
+        let registry = ProfileRegistry::from_file(&path).unwrap();
+        assert_eq!(registry.name(108934), Some("Rider"));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use zwift_capture::Player;
+use crate::export::escape_csv;
+use crate::profiles::ProfileRegistry;
+use crate::{PlayerData, World};
+
+
+/// Line between two points on the world map, crossed by riders
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct Gate {
+    pub x1: f64,
+    pub y1: f64,
+    pub x2: f64,
+    pub y2: f64
+}
+
+impl Gate {
+    /// Part of the way from `before` to `after` where gate is crossed, 0..1
+    pub fn crossing(&self, before: &Player, after: &Player) -> Option<f64> {
+        let (rx, ry) = (after.x - before.x, after.y - before.y);
+        let (sx, sy) = (self.x2 - self.x1, self.y2 - self.y1);
+        let denominator = rx * sy - ry * sx;
+        if denominator.abs() < f64::EPSILON {
+            return None;
+        }
+        let (qx, qy) = (self.x1 - before.x, self.y1 - before.y);
+        let t = (qx * sy - qy * sx) / denominator;
+        let u = (qx * ry - qy * rx) / denominator;
+        // crossing exactly at previous sample was counted with the previous move
+        if t > 0. && t <= 1. && (0. ..=1.).contains(&u) {
+            Some(t)
+        } else {
+            None
+        }
+    }
+}
+
+pub fn interpolate_time(before: &Player, after: &Player, ratio: f64) -> i64 {
+    before.world_time + ((after.world_time - before.world_time) as f64 * ratio).round() as i64
+}
+
+/// Race time as `H:MM:SS.mmm`
+pub fn format_race_time(time: i64) -> String {
+    let millis = time.abs() % 1000;
+    let seconds = time.abs() / 1000;
+    let sign = if time < 0 { "-" } else { "" };
+    format!("{}{}:{:02}:{:02}.{:03}", sign, seconds / 3600, seconds / 60 % 60, seconds % 60, millis)
+}
+
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RaceFinish {
+    /// meters from rider's start
+    Distance { distance: i32 },
+    /// completed laps from rider's start, by `Player.laps`
+    Laps { laps: i32 }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct RaceConfig {
+    pub name: Option<String>,
+    /// mass start world time, race starts immediately if neither start time nor line is set
+    pub start_time: Option<i64>,
+    /// riders start when crossing the line, each one with own start time,
+    /// also used as lap line to time lap ends
+    pub start_line: Option<Gate>,
+    pub finish: RaceFinish,
+    /// all riders of the group take part, watch group if not set
+    pub group_id: Option<i32>
+}
+
+#[derive(Debug,Clone)]
+struct RaceRider {
+    start_time: i64,
+    start_distance: f64,
+    start_laps: i32,
+    distance: i32,
+    laps: i32,
+    finish_time: Option<i64>
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct RaceResult {
+    pub position: usize,
+    pub id: i32,
+    pub name: Option<String>,
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+    /// finish time from start, ms
+    pub time: Option<i64>,
+    /// behind the winner, ms
+    pub gap: Option<i64>,
+    pub distance: i32,
+    pub laps: i32
+}
+
+
+/// Start and finish crossings for a single race, live standings
+#[derive(Debug,Default)]
+pub struct RaceTracker {
+    config: Option<RaceConfig>,
+    riders: HashMap<i32, RaceRider>,
+    // previous sample of every participant, for line crossings
+    last_samples: HashMap<i32, Player>
+}
+
+impl RaceTracker {
+    pub fn new() -> Self {
+        RaceTracker::default()
+    }
+
+    pub fn config(&self) -> Option<&RaceConfig> {
+        self.config.as_ref()
+    }
+
+    /// Rider started current race
+    pub fn has_rider(&self, player_id: i32) -> bool {
+        self.riders.contains_key(&player_id)
+    }
+
+    /// Replace current race, results are cleared
+    pub fn start(&mut self, mut config: RaceConfig, world_time: i64) {
+        if config.start_time.is_none() && config.start_line.is_none() {
+            config.start_time = Some(world_time);
+        }
+        self.config = Some(config);
+        self.riders.clear();
+        self.last_samples.clear();
+    }
+
+    pub fn stop(&mut self) {
+        self.config = None;
+        self.riders.clear();
+        self.last_samples.clear();
+    }
+
+    fn participants(config: &RaceConfig, world: &World) -> Vec<i32> {
+        match config.group_id {
+            Some(group_id) => world.get_group(group_id)
+                .map(|group| group.iter().collect())
+                .unwrap_or_default(),
+            None => world.group_to_watch.iter().collect()
+        }
+    }
+
+    fn try_start(config: &RaceConfig, player_data: &PlayerData, previous: Option<&Player>, player: &Player) -> Option<RaceRider> {
+        let (start_time, start_distance) = match (&config.start_line, config.start_time) {
+            (Some(line), _) => {
+                let previous = previous?;
+                let ratio = line.crossing(previous, player)?;
+                let distance = previous.distance as f64 + (player.distance - previous.distance) as f64 * ratio;
+                (interpolate_time(previous, player, ratio), distance)
+            },
+            (None, Some(start_time)) if player.world_time >= start_time => {
+                let distance = player_data.get_at_time(start_time)
+                    .map(|start| start.distance)
+                    .unwrap_or(player.distance);
+                (start_time, distance as f64)
+            },
+            _ => return None
+        };
+        Some(RaceRider {
+            start_time,
+            start_distance,
+            start_laps: player.laps,
+            distance: 0,
+            laps: 0,
+            finish_time: None
+        })
+    }
+
+    fn update_rider(config: &RaceConfig, world: &World, rider: &mut RaceRider, previous: Option<&Player>, player: &Player) {
+        let previous_distance = rider.distance;
+        rider.distance = (player.distance as f64 - rider.start_distance).max(0.) as i32;
+        rider.laps = player.laps - rider.start_laps;
+        rider.finish_time = match config.finish {
+            RaceFinish::Distance { distance } if rider.distance >= distance => {
+                match previous {
+                    Some(previous) if previous_distance < distance => {
+                        let ratio = (distance - previous_distance) as f64 / (rider.distance - previous_distance) as f64;
+                        Some(interpolate_time(previous, player, ratio).max(rider.start_time))
+                    },
+                    _ => Some(player.world_time)
+                }
+            },
+            // line crossing interpolated by lap tracker, the last lap ends with the race
+            RaceFinish::Laps { laps } if rider.laps >= laps => {
+                let end_time = world.laps.lap_end_time(player.id, rider.start_laps + laps - 1)
+                    .unwrap_or(player.world_time);
+                Some(end_time.max(rider.start_time))
+            },
+            _ => None
+        };
+    }
+
+    /// Start and finish checks for participants with new samples only
+    pub fn update(&mut self, world: &World, updated_players: &HashSet<i32>) {
+        let config = match &self.config {
+            Some(config) => config,
+            _ => return
+        };
+        let participants = RaceTracker::participants(config, world).into_iter()
+            .filter(|player_id| updated_players.contains(player_id));
+        for player_id in participants {
+            let player_data = match world.get_player_data(player_id) {
+                Some(player_data) => player_data,
+                _ => continue
+            };
+            let player = match player_data.get_latest() {
+                Some(player) => player,
+                _ => continue
+            };
+            let previous = self.last_samples.get(&player_id);
+            if previous.map(|previous| previous.world_time >= player.world_time).unwrap_or(false) {
+                continue;
+            }
+            let finished = self.riders.get(&player_id)
+                .map(|rider| rider.finish_time.is_some())
+                .unwrap_or(false);
+            if !finished {
+                if let Some(rider) = self.riders.get_mut(&player_id) {
+                    RaceTracker::update_rider(config, world, rider, previous, &player);
+                } else if let Some(rider) = RaceTracker::try_start(config, player_data, previous, &player) {
+                    self.riders.insert(player_id, rider);
+                }
+            }
+            self.last_samples.insert(player_id, player);
+        }
+    }
+
+    /// Finished riders by time, then the rest by covered distance
+    pub fn results(&self, profiles: &ProfileRegistry) -> Vec<RaceResult> {
+        let mut riders: Vec<(&i32, &RaceRider)> = self.riders.iter().collect();
+        riders.sort_by(|(_, a), (_, b)| {
+            match (a.finish_time, b.finish_time) {
+                (Some(_), Some(_)) => (a.finish_time.unwrap() - a.start_time).cmp(&(b.finish_time.unwrap() - b.start_time)),
+                (Some(_), None) => std::cmp::Ordering::Less,
+                (None, Some(_)) => std::cmp::Ordering::Greater,
+                (None, None) => b.distance.cmp(&a.distance)
+            }
+        });
+        let mut winner_time = None;
+        let mut result = Vec::with_capacity(riders.len());
+        for (ix, (&id, rider)) in riders.into_iter().enumerate() {
+            let time = rider.finish_time.map(|finish_time| finish_time - rider.start_time);
+            if winner_time.is_none() {
+                winner_time = time;
+            }
+            result.push(RaceResult {
+                position: ix + 1,
+                id,
+                name: profiles.name(id).map(String::from),
+                started_at: rider.start_time,
+                finished_at: rider.finish_time,
+                time,
+                gap: time.and_then(|time| winner_time.map(|winner_time| time - winner_time)),
+                distance: rider.distance,
+                laps: rider.laps
+            });
+        }
+        result
+    }
+}
+
+pub fn results_to_csv(results: &[RaceResult]) -> String {
+    let mut output = String::from("position,id,name,time,gap,distance,laps,started_at,finished_at\n");
+    for result in results.iter() {
+        let row = vec![
+            result.position.to_string(),
+            result.id.to_string(),
+            escape_csv(result.name.as_deref().unwrap_or("")),
+            result.time.map(format_race_time).unwrap_or_default(),
+            result.gap.map(format_race_time).unwrap_or_default(),
+            result.distance.to_string(),
+            result.laps.to_string(),
+            result.started_at.to_string(),
+            result.finished_at.map(|time| time.to_string()).unwrap_or_default()
+        ];
+        output.push_str(&row.join(","));
+        output.push('\n');
+    }
+    output
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn race_gate_crossing() {
+        let gate = Gate { x1: 10., y1: -5., x2: 10., y2: 5. };
+        let mut before = get_player_instance();
+        before.x = 0.;
+        before.y = 0.;
+        let mut after = before.clone();
+        after.x = 40.;
+        assert_eq!(gate.crossing(&before, &after), Some(0.25));
+        after.y = 40.;
+        assert_eq!(gate.crossing(&before, &after), None);
+        assert_eq!(format_race_time(3_723_456), "1:02:03.456");
+    }
+
+    #[test]
+    fn race_tracker_results() {
+        let mut world = World::new();
+        let base_player = get_player_instance();
+        world.race.start(RaceConfig {
+            name: None,
+            start_time: None,
+            start_line: Some(Gate { x1: 100., y1: -10., x2: 100., y2: 10. }),
+            finish: RaceFinish::Distance { distance: 500 },
+            group_id: None
+        }, base_player.world_time);
+        // riders 10 m/s and 12.5 m/s, rider 3 does not reach the line
+        for step in 0..65 {
+            for (id, speed) in [(1, 10.), (2, 12.5), (3, 0.)].iter() {
+                let mut player = base_player.clone();
+                player.id = *id;
+                player.world_time = base_player.world_time + step * 1000;
+                player.x = step as f64 * speed;
+                player.y = 0.;
+                player.distance = (step as f64 * speed) as _;
+                world.push_player(player);
+            }
+            if step == 0 {
+                world.add_player_to_watch(1);
+                world.add_player_to_watch(2);
+                world.add_player_to_watch(3);
+            }
+            world.push_players_batch(Vec::new());
+        }
+        let results = world.race.results(&world.profiles);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].id, 2);
+        assert_eq!(results[0].started_at, base_player.world_time + 8000);
+        assert_eq!(results[0].time, Some(40_000));
+        assert_eq!(results[1].id, 1);
+        assert_eq!(results[1].time, Some(50_000));
+        assert_eq!(results[1].gap, Some(10_000));
+        let csv = results_to_csv(&results);
+        assert_eq!(csv.lines().nth(1).unwrap().split(',').nth(3), Some("0:00:40.000"));
+    }
+
+    #[test]
+    fn race_tracker_laps_finish() {
+        let mut world = World::new();
+        let base_player = get_player_instance();
+        world.race.start(RaceConfig {
+            name: None,
+            start_time: Some(base_player.world_time),
+            start_line: None,
+            finish: RaceFinish::Laps { laps: 2 },
+            group_id: None
+        }, base_player.world_time);
+        world.add_player_to_watch(base_player.id);
+        // new lap every 10 sec, counter changes between samples
+        for step in 0..25 {
+            let mut player = base_player.clone();
+            player.world_time = base_player.world_time + step * 1000;
+            player.distance = (step * 100) as _;
+            player.laps = (step / 10) as _;
+            world.push_players_batch(vec![player]);
+        }
+        let results = world.race.results(&world.profiles);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].laps, 2);
+        assert_eq!(results[0].finished_at, Some(base_player.world_time + 19_500));
+        assert_eq!(results[0].time, Some(19_500));
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use zwift_capture::Player;
+use crate::race::{Gate, interpolate_time};
+use crate::profiles::ProfileRegistry;
+use crate::{road_position_jump, World};
+
+
+// step between interpolated samples used to find crossings
+const SEGMENT_SAMPLE_STEP: i64 = 100;
+// longer pauses between updates (no data) are not interpolated
+const SEGMENT_MAX_SAMPLE_GAP: i64 = 5000;
+const SEGMENT_EFFORTS_CAPACITY: usize = 10_000;
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SegmentBoundary {
+    Gate {
+        #[serde(flatten)]
+        gate: Gate
+    },
+    /// `Player.road_position` value, crossed in any direction,
+    /// on given road only as positions of different roads overlap.
+    /// Steps with road change or jump of position (wraparound, teleport) don't cross it
+    RoadPosition {
+        road_position: i32,
+        #[serde(default)]
+        road_id: Option<i32>
+    }
+}
+
+impl SegmentBoundary {
+    /// Part of the way from `before` to `after` where boundary is crossed, 0..1,
+    /// `player_road_id` is current road of the rider if known, `road_changed` if it was different before
+    pub fn crossing(&self, before: &Player, after: &Player, player_road_id: Option<i32>, road_changed: bool) -> Option<f64> {
+        match self {
+            SegmentBoundary::Gate { gate } => gate.crossing(before, after),
+            SegmentBoundary::RoadPosition { road_position, road_id } => {
+                if road_changed || road_position_jump(before, after) {
+                    return None;
+                }
+                if road_id.is_some() && *road_id != player_road_id {
+                    return None;
+                }
+                let value = *road_position as f64;
+                let (from, to) = (before.road_position as f64, after.road_position as f64);
+                let crossed = (from < value && to >= value) || (from > value && to <= value);
+                match crossed {
+                    true => Some((value - from) / (to - from)),
+                    false => None
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentKind {
+    Sprint,
+    Kom,
+    Segment
+}
+
+impl Default for SegmentKind {
+    fn default() -> Self {
+        SegmentKind::Segment
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct Segment {
+    /// assigned when segment is added
+    #[serde(default)]
+    pub id: usize,
+    pub name: String,
+    #[serde(default)]
+    pub kind: SegmentKind,
+    /// Zwift world of the segment, matched against `World.zwift_world_id`;
+    /// player states don't carry the world, such segment is never timed unless the watcher runs with `--world-id`
+    #[serde(default)]
+    pub world_id: Option<i32>,
+    pub start: SegmentBoundary,
+    pub end: SegmentBoundary
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct SegmentEffort {
+    pub segment_id: usize,
+    pub player_id: i32,
+    pub start_time: i64,
+    pub end_time: i64,
+    /// ms
+    pub time: i64,
+    pub average_power: f64
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct LeaderboardEntry {
+    pub position: usize,
+    pub player_id: i32,
+    pub name: Option<String>,
+    pub time: i64,
+    pub gap: i64,
+    pub average_power: f64,
+    pub end_time: i64
+}
+
+#[derive(Debug,Clone)]
+struct ActiveEffort {
+    start_time: i64,
+    // power integrated over time, W*ms
+    energy: f64
+}
+
+#[derive(Debug)]
+struct SegmentRider {
+    last: Player,
+    // road at the last update
+    road_id: Option<i32>,
+    active: HashMap<usize, ActiveEffort>
+}
+
+
+/// Riders entering and exiting segments, with completed efforts of current event
+#[derive(Debug,Default)]
+pub struct SegmentTracker {
+    segments: Vec<Segment>,
+    riders: HashMap<i32, SegmentRider>,
+    efforts: VecDeque<SegmentEffort>
+}
+
+impl SegmentTracker {
+    pub fn new() -> Self {
+        SegmentTracker::default()
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn get_segment(&self, segment_id: usize) -> Option<&Segment> {
+        self.segments.get(segment_id)
+    }
+
+    pub fn add_segment(&mut self, mut segment: Segment) -> &Segment {
+        segment.id = self.segments.len();
+        self.segments.push(segment);
+        &self.segments[self.segments.len() - 1]
+    }
+
+    /// Remove segments and recorded efforts
+    pub fn clear(&mut self) {
+        self.segments.clear();
+        self.riders.clear();
+        self.efforts.clear();
+    }
+
+    /// Start new event with the same segments
+    pub fn reset_efforts(&mut self) {
+        self.riders.clear();
+        self.efforts.clear();
+    }
+
+    fn push_effort(&mut self, effort: SegmentEffort) {
+        if self.efforts.len() >= SEGMENT_EFFORTS_CAPACITY {
+            self.efforts.pop_front();
+        }
+        self.efforts.push_back(effort);
+    }
+
+    fn process_step(&mut self, player_id: i32, before: &Player, after: &Player, world_id: Option<i32>, road_id: Option<i32>, road_changed: bool) {
+        let duration = (after.world_time - before.world_time) as f64;
+        let mut completed = Vec::new();
+        if let Some(rider) = self.riders.get_mut(&player_id) {
+            let segments = self.segments.iter()
+                .filter(|segment| segment.world_id.is_none() || segment.world_id == world_id);
+            for segment in segments {
+                if let Some(mut effort) = rider.active.remove(&segment.id) {
+                    if let Some(ratio) = segment.end.crossing(before, after, road_id, road_changed) {
+                        effort.energy += before.power as f64 * duration * ratio;
+                        let end_time = interpolate_time(before, after, ratio);
+                        let time = end_time - effort.start_time;
+                        completed.push(SegmentEffort {
+                            segment_id: segment.id,
+                            player_id,
+                            start_time: effort.start_time,
+                            end_time,
+                            time,
+                            average_power: match time > 0 {
+                                true => effort.energy / time as f64,
+                                false => before.power as f64
+                            }
+                        });
+                        continue;
+                    }
+                    effort.energy += before.power as f64 * duration;
+                    rider.active.insert(segment.id, effort);
+                } else if let Some(ratio) = segment.start.crossing(before, after, road_id, road_changed) {
+                    rider.active.insert(segment.id, ActiveEffort {
+                        start_time: interpolate_time(before, after, ratio),
+                        energy: before.power as f64 * duration * (1. - ratio)
+                    });
+                }
+            }
+        }
+        for effort in completed.into_iter() {
+            self.push_effort(effort);
+        }
+    }
+
+    pub fn update(&mut self, world: &World) {
+        if self.segments.is_empty() {
+            return;
+        }
+        for player_id in world.group_to_watch.iter() {
+            let player_data = match world.get_player_data(player_id) {
+                Some(player_data) => player_data,
+                _ => continue
+            };
+            let latest = match player_data.get_latest() {
+                Some(latest) => latest,
+                _ => continue
+            };
+            let road_id = world.get_road_id(player_id);
+            let (mut last, road_changed) = match self.riders.get(&player_id) {
+                Some(rider) if latest.world_time <= rider.last.world_time => continue,
+                Some(rider) if latest.world_time - rider.last.world_time <= SEGMENT_MAX_SAMPLE_GAP => {
+                    (rider.last.clone(), rider.road_id.is_some() && road_id.is_some() && rider.road_id != road_id)
+                },
+                _ => {
+                    self.riders.insert(player_id, SegmentRider { last: latest, road_id, active: HashMap::new() });
+                    continue;
+                }
+            };
+            // crossings between interpolated samples, follows the road better than raw updates
+            let mut time = last.world_time;
+            while time < latest.world_time {
+                time = (time + SEGMENT_SAMPLE_STEP).min(latest.world_time);
+                let current = match player_data.get_at_time(time) {
+                    Some(current) => current,
+                    _ => continue
+                };
+                self.process_step(player_id, &last, &current, world.zwift_world_id, road_id, road_changed);
+                last = current;
+            }
+            if let Some(rider) = self.riders.get_mut(&player_id) {
+                rider.last = last;
+                rider.road_id = road_id.or(rider.road_id);
+            }
+        }
+    }
+
+    pub fn all_efforts(&self) -> &VecDeque<SegmentEffort> {
+        &self.efforts
+    }
+
+    pub fn efforts(&self, segment_id: usize) -> Vec<SegmentEffort> {
+        self.efforts.iter().filter(|effort| effort.segment_id == segment_id).cloned().collect()
+    }
+
+    /// Best effort of every rider on the segment, fastest first
+    pub fn leaderboard(&self, segment_id: usize, profiles: &ProfileRegistry) -> Vec<LeaderboardEntry> {
+        let mut best: HashMap<i32, &SegmentEffort> = HashMap::new();
+        for effort in self.efforts.iter().filter(|effort| effort.segment_id == segment_id) {
+            let current = best.entry(effort.player_id).or_insert(effort);
+            if effort.time < current.time {
+                *current = effort;
+            }
+        }
+        let mut efforts: Vec<&SegmentEffort> = best.into_iter().map(|(_, effort)| effort).collect();
+        efforts.sort_by_key(|effort| (effort.time, effort.end_time));
+        let best_time = efforts.first().map(|effort| effort.time).unwrap_or(0);
+        efforts.into_iter()
+            .enumerate()
+            .map(|(ix, effort)| LeaderboardEntry {
+                position: ix + 1,
+                player_id: effort.player_id,
+                name: profiles.name(effort.player_id).map(String::from),
+                time: effort.time,
+                gap: effort.time - best_time,
+                average_power: effort.average_power,
+                end_time: effort.end_time
+            })
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn segment_tracker_leaderboard() {
+        let mut world = World::new();
+        let segment: Segment = serde_json::from_str(r#"{
+            "name": "Sprint",
+            "kind": "sprint",
+            "start": {"type": "gate", "x1": 100.0, "y1": -10.0, "x2": 100.0, "y2": 10.0},
+            "end": {"type": "gate", "x1": 300.0, "y1": -10.0, "x2": 300.0, "y2": 10.0}
+        }"#).unwrap();
+        assert_eq!(world.segments.add_segment(segment).id, 0);
+        let base_player = get_player_instance();
+        // updates every 1.5 sec, rider 2 is faster and stronger
+        for step in 0..30 {
+            for (id, speed, power) in [(1, 10., 200), (2, 20., 400)].iter() {
+                let mut player = base_player.clone();
+                player.id = *id;
+                player.world_time = base_player.world_time + step * 1500;
+                player.x = step as f64 * 1.5 * speed;
+                player.y = 0.;
+                player.power = *power as _;
+                world.push_player(player);
+            }
+            if step == 0 {
+                world.add_player_to_watch(1);
+                world.add_player_to_watch(2);
+            }
+            world.push_players_batch(Vec::new());
+        }
+        let leaderboard = world.segments.leaderboard(0, &world.profiles);
+        assert_eq!(leaderboard.len(), 2);
+        assert_eq!(leaderboard[0].player_id, 2);
+        assert_eq!(leaderboard[0].time, 10_000);
+        assert!((leaderboard[0].average_power - 400.).abs() < 0.001);
+        assert_eq!(leaderboard[1].player_id, 1);
+        assert_eq!(leaderboard[1].time, 20_000);
+        assert_eq!(leaderboard[1].gap, 10_000);
+        let efforts = world.segments.efforts(0);
+        assert_eq!(efforts[0].start_time, base_player.world_time + 5000);
+    }
+
+    #[test]
+    fn segment_tracker_road_and_world() {
+        let mut world = World::new();
+        world.zwift_world_id = Some(1);
+        for world_id in [Some(1), Some(2)].iter() {
+            world.segments.add_segment(Segment {
+                id: 0,
+                name: String::from("Road"),
+                kind: SegmentKind::Segment,
+                world_id: *world_id,
+                start: SegmentBoundary::RoadPosition { road_position: 1000, road_id: Some(5) },
+                end: SegmentBoundary::RoadPosition { road_position: 3000, road_id: Some(5) }
+            });
+        }
+        // rider 1 is on the segment road, rider 2 on other road with the same positions
+        world.push_road_ids(vec![(1, 5), (2, 7)]);
+        let base_player = get_player_instance();
+        for step in 0..10 {
+            for id in [1, 2].iter() {
+                let mut player = base_player.clone();
+                player.id = *id;
+                player.world_time = base_player.world_time + step * 1000;
+                player.road_position = step as i32 * 500;
+                world.push_player(player);
+            }
+            if step == 0 {
+                world.add_player_to_watch(1);
+                world.add_player_to_watch(2);
+            }
+            world.push_players_batch(Vec::new());
+        }
+        let efforts = world.segments.efforts(0);
+        assert_eq!(efforts.len(), 1);
+        assert_eq!(efforts[0].player_id, 1);
+        assert_eq!(efforts[0].time, 4000);
+        assert!(world.segments.efforts(1).is_empty());
+    }
+
+    #[test]
+    fn segment_road_position_jumps() {
+        let mut world = World::new();
+        world.segments.add_segment(Segment {
+            id: 0,
+            name: String::from("Road"),
+            kind: SegmentKind::Segment,
+            world_id: None,
+            start: SegmentBoundary::RoadPosition { road_position: 1000, road_id: None },
+            end: SegmentBoundary::RoadPosition { road_position: 3000, road_id: None }
+        });
+        world.push_road_ids(vec![(1, 5), (2, 5)]);
+        let base_player = get_player_instance();
+        for step in 0..10 {
+            for id in [1, 2].iter() {
+                let mut player = base_player.clone();
+                player.id = *id;
+                player.world_time = base_player.world_time + step * 1000;
+                player.road_position = match (*id, step) {
+                    // rider 1 wraps around the road end at 5th second, crossing start backwards
+                    (1, step) if step >= 5 => 900_000 - (step as i32 - 5) * 500,
+                    // rider 2 moves to other road with overlapping positions
+                    (2, step) if step >= 5 => 500 - (step as i32 - 5) * 50,
+                    _ => 1500 - step as i32 * 100
+                };
+                world.push_player(player);
+            }
+            if step == 0 {
+                world.add_player_to_watch(1);
+                world.add_player_to_watch(2);
+            }
+            if step == 5 {
+                world.push_road_ids(vec![(2, 7)]);
+            }
+            world.push_players_batch(Vec::new());
+        }
+        assert!(world.segments.efforts(0).is_empty());
+        assert!(world.segments.riders.values().all(|rider| rider.active.is_empty()));
+    }
+}
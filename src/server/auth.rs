@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use warp::http::Method;
+use warp::{Filter, Rejection};
+
+
+const TOKEN_QUERY_PARAM: &str = "access_token";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// GET requests
+    ReadOnly,
+    /// all requests, including changes of watch group, rules, profiles etc.
+    Admin
+}
+
+impl Scope {
+    pub fn required_for(method: &Method) -> Self {
+        match *method {
+            Method::GET | Method::HEAD | Method::OPTIONS => Scope::ReadOnly,
+            _ => Scope::Admin
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TokenConfig {
+    pub token: String,
+    pub scope: Scope
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub enum AuthError {
+    /// no token or unknown token
+    Unauthorized,
+    /// token scope is not enough
+    Forbidden
+}
+
+impl warp::reject::Reject for AuthError {}
+
+
+/// API tokens, requests are not checked if there are no tokens
+#[derive(Debug,Clone,Default)]
+pub struct Auth {
+    tokens: HashMap<String, Scope>
+}
+
+impl Auth {
+    pub fn new() -> Self {
+        Auth::default()
+    }
+
+    /// JSON list of `{"token": "...", "scope": "read_only" | "admin"}`
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let tokens: Vec<TokenConfig> = serde_json::from_str(&content).map_err(|err| err.to_string())?;
+        let mut auth = Auth::new();
+        for config in tokens.into_iter() {
+            auth.add_token(&config.token, config.scope);
+        }
+        Ok(auth)
+    }
+
+    pub fn add_token(&mut self, token: &str, scope: Scope) {
+        if !token.is_empty() {
+            self.tokens.insert(String::from(token), scope);
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    fn scope(&self, token: &str) -> Option<Scope> {
+        // compare every token in full, don't leak matching prefix length by timing
+        let mut result = None;
+        for (known, scope) in self.tokens.iter() {
+            let same_length = known.len() == token.len();
+            let difference = known.bytes().zip(token.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b));
+            if same_length && difference == 0 {
+                result = Some(*scope);
+            }
+        }
+        result
+    }
+
+    pub fn authorize(&self, method: &Method, token: Option<&str>) -> Result<(), AuthError> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let scope = token.and_then(|token| self.scope(token)).ok_or(AuthError::Unauthorized)?;
+        match scope >= Scope::required_for(method) {
+            true => Ok(()),
+            false => Err(AuthError::Forbidden)
+        }
+    }
+}
+
+/// Token from `Authorization: Bearer`, `X-Api-Key` header or `access_token` query param
+/// (for browser sources which can't set headers), query values are already percent-decoded
+fn request_token(authorization: Option<String>, api_key: Option<String>, query: &HashMap<String, String>) -> Option<String> {
+    let bearer = authorization.as_ref()
+        .and_then(|value| value.trim().split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"));
+    if let Some((_, token)) = bearer {
+        return Some(String::from(token.trim()));
+    }
+    if api_key.is_some() {
+        return api_key;
+    }
+    query.get(TOKEN_QUERY_PARAM).cloned()
+}
+
+pub fn authorize(auth: Arc<Auth>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(warp::query::<HashMap<String, String>>().or(warp::any().map(HashMap::new)).unify())
+        .and_then(move |method: Method, authorization: Option<String>, api_key: Option<String>, query: HashMap<String, String>| {
+            let auth = auth.clone();
+            async move {
+                let token = request_token(authorization, api_key, &query);
+                auth.authorize(&method, token.as_deref()).map_err(warp::reject::custom)
+            }
+        })
+        .untuple_one()
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn auth_authorize() {
+        let mut auth = Auth::new();
+        assert_eq!(auth.authorize(&Method::POST, None), Ok(()));
+        auth.add_token("read", Scope::ReadOnly);
+        auth.add_token("admin", Scope::Admin);
+        assert_eq!(auth.authorize(&Method::GET, None), Err(AuthError::Unauthorized));
+        assert_eq!(auth.authorize(&Method::GET, Some("rea")), Err(AuthError::Unauthorized));
+        assert_eq!(auth.authorize(&Method::GET, Some("read")), Ok(()));
+        assert_eq!(auth.authorize(&Method::DELETE, Some("read")), Err(AuthError::Forbidden));
+        assert_eq!(auth.authorize(&Method::DELETE, Some("admin")), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn auth_filter() {
+        let mut auth = Auth::new();
+        auth.add_token("read", Scope::ReadOnly);
+        auth.add_token("admin", Scope::Admin);
+        let filter = authorize(Arc::new(auth)).map(warp::reply);
+
+        let request = || warp::test::request().path("/watch/add");
+        assert!(request().method("GET").header("authorization", "Bearer read").matches(&filter).await);
+        assert!(request().method("GET").header("x-api-key", "read").matches(&filter).await);
+        assert!(warp::test::request().path("/watch?access_token=read").matches(&filter).await);
+        assert!(request().method("GET").header("authorization", "bearer read").matches(&filter).await);
+        assert!(!request().method("GET").header("authorization", "Basic read").matches(&filter).await);
+        assert!(!request().method("POST").header("authorization", "Bearer read").matches(&filter).await);
+        assert!(request().method("POST").header("authorization", "Bearer admin").matches(&filter).await);
+        assert!(!request().method("GET").matches(&filter).await);
+
+        let mut auth = Auth::new();
+        auth.add_token("a+b/c=", Scope::ReadOnly);
+        let filter = authorize(Arc::new(auth)).map(warp::reply);
+        assert!(warp::test::request().path("/watch?access_token=a%2Bb%2Fc%3D").matches(&filter).await);
+        assert!(!warp::test::request().path("/watch?access_token=a+b/c=").matches(&filter).await);
+    }
+}
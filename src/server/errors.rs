@@ -0,0 +1,138 @@
+use std::convert::Infallible;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+use super::auth::AuthError;
+
+
+/// Handler error with HTTP status, answered as `{"result": "error", "error": {"code", "message"}}`
+#[derive(Debug,Clone,PartialEq)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub message: String
+}
+
+impl ApiError {
+    pub fn bad_request(message: &str) -> Self {
+        ApiError {
+            status: StatusCode::BAD_REQUEST,
+            message: String::from(message)
+        }
+    }
+
+    pub fn not_found(message: &str) -> Self {
+        ApiError {
+            status: StatusCode::NOT_FOUND,
+            message: String::from(message)
+        }
+    }
+
+    pub fn internal(message: &str) -> Self {
+        ApiError {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: String::from(message)
+        }
+    }
+}
+
+impl warp::reject::Reject for ApiError {}
+
+/// Zwift player ids are positive
+pub fn validate_player_id(player_id: i32) -> Result<i32, ApiError> {
+    match player_id > 0 {
+        true => Ok(player_id),
+        false => Err(ApiError::bad_request(&format!("Invalid player id: {}", player_id)))
+    }
+}
+
+pub fn error_reply(status: StatusCode, message: &str) -> impl Reply {
+    warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({
+            "result": "error",
+            "error": {
+                "code": status.as_u16(),
+                "message": message
+            }
+        })),
+        status
+    )
+}
+
+fn rejection_status(rejection: &Rejection) -> (StatusCode, String) {
+    if rejection.is_not_found() {
+        return (StatusCode::NOT_FOUND, String::from("Not found"));
+    }
+    if let Some(error) = rejection.find::<ApiError>() {
+        return (error.status, error.message.clone());
+    }
+    if let Some(error) = rejection.find::<AuthError>() {
+        return match error {
+            AuthError::Unauthorized => (StatusCode::UNAUTHORIZED, String::from("Missing or invalid API token")),
+            AuthError::Forbidden => (StatusCode::FORBIDDEN, String::from("API token is read only"))
+        };
+    }
+    if let Some(error) = rejection.find::<warp::filters::body::BodyDeserializeError>() {
+        return (StatusCode::BAD_REQUEST, error.to_string());
+    }
+    if let Some(error) = rejection.find::<warp::reject::InvalidQuery>() {
+        return (StatusCode::BAD_REQUEST, error.to_string());
+    }
+    if let Some(error) = rejection.find::<warp::reject::UnsupportedMediaType>() {
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, error.to_string());
+    }
+    if let Some(error) = rejection.find::<warp::reject::LengthRequired>() {
+        return (StatusCode::LENGTH_REQUIRED, error.to_string());
+    }
+    if let Some(error) = rejection.find::<warp::reject::PayloadTooLarge>() {
+        return (StatusCode::PAYLOAD_TOO_LARGE, error.to_string());
+    }
+    if let Some(error) = rejection.find::<warp::reject::MethodNotAllowed>() {
+        return (StatusCode::METHOD_NOT_ALLOWED, error.to_string());
+    }
+    (StatusCode::INTERNAL_SERVER_ERROR, String::from("Internal server error"))
+}
+
+/// JSON error response for every rejection
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, message) = rejection_status(&rejection);
+    Ok(error_reply(status, &message))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use warp::Filter;
+
+    #[tokio::test]
+    async fn errors_handle_rejection() {
+        let filter = warp::post()
+            .and(warp::path!("players" / i32))
+            .and(warp::body::json::<serde_json::Value>())
+            .and_then(|player_id: i32, _body: serde_json::Value| async move {
+                validate_player_id(player_id)?;
+                Err::<String, Rejection>(ApiError::not_found("Player not found").into())
+            })
+            .recover(handle_rejection);
+
+        let response = warp::test::request().method("POST").path("/players/5").body("{").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["result"], "error");
+        assert_eq!(body["error"]["code"], 400);
+
+        let response = warp::test::request().method("POST").path("/players/0").body("{}").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.body(), r#"{"error":{"code":400,"message":"Invalid player id: 0"},"result":"error"}"#);
+
+        let response = warp::test::request().method("POST").path("/players/5").body("{}").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = warp::test::request().method("POST").path("/unknown").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.body(), r#"{"error":{"code":404,"message":"Not found"},"result":"error"}"#);
+
+        let response = warp::test::request().method("GET").path("/players/5").reply(&filter).await;
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
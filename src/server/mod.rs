@@ -1,35 +1,152 @@
-use warp::{Filter, Rejection};
+use warp::Filter;
 use std::sync::{Arc, Mutex};
-use std::iter::Map;
+use std::time::Duration;
+use std::convert::Infallible;
 use crate::{World,PLAYER_GROUP_CAPACITY};
+use crate::storage::Storage;
+use crate::profiles::PlayerProfile;
+use crate::watch_rules::WatchRule;
+use crate::spatial::{NearbyMode, MAX_NEARBY_RADIUS};
+use crate::race::{RaceConfig, results_to_csv};
+use crate::segments::Segment;
+use crate::points::{self, PointsConfig};
+use crate::teams::{self, TeamConfig};
+use crate::map::{self, MapOptions, MAP_MIN_SIZE};
+use crate::overlay::{self, OverlayTemplates};
+use crate::metrics::{Metrics, RiderTelemetry, render_riders};
+use auth::Auth;
+use errors::{ApiError, validate_player_id};
+
+const DEFAULT_NEARBY_RADIUS: f64 = 100.;
+const STREAM_INTERVAL: Duration = Duration::from_secs(1);
+const DASHBOARD_HTML: &str = include_str!("../../static/dashboard.html");
+
+pub mod auth;
+pub mod errors;
+pub mod openapi;
+pub mod tls;
 
 pub mod models {
+    use std::collections::HashMap;
+    use schemars::JsonSchema;
     use serde::{Serialize, Deserialize};
+    use crate::profiles::{PlayerProfile, PlayerRecord};
+    use crate::laps::LapSplit;
+    use crate::points::{PointsConfig, PointsStandings};
+    use crate::race::RaceResult;
+    use crate::segments::{LeaderboardEntry, Segment, SegmentEffort};
+    use crate::teams::TeamResult;
 
-    #[derive(Debug, Deserialize, Serialize, Clone)]
+    #[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
     pub struct PLayerQuery {
         pub id: i32
     }
 
-    #[derive(Debug, Deserialize, Serialize, Clone)]
+    #[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
     pub struct WatchOptions {
         pub latest: Option<String>
     }
+
+    #[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+    pub struct PlayerTimeQuery {
+        pub time: Option<i64>
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+    pub struct EventsQuery {
+        /// only events with greater id
+        pub since: Option<u64>
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+    pub struct ResultsQuery {
+        /// `json` (default) or `csv`
+        pub format: Option<String>
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+    pub struct MapQuery {
+        pub width: Option<u32>,
+        pub height: Option<u32>,
+        pub trails: Option<bool>,
+        pub labels: Option<bool>,
+        /// only watch group riders
+        pub watch: Option<bool>
+    }
+
+    #[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
+    pub struct NearbyQuery {
+        pub radius: Option<f64>,
+        /// `euclidean` (default) or `road`
+        pub mode: Option<String>
+    }
+
+    #[derive(Debug, Serialize, Clone, JsonSchema)]
+    pub struct WorldState {
+        pub world_time: i64,
+        pub group_to_watch: Vec<i32>
+    }
+
+    #[derive(Debug, Serialize, Clone, JsonSchema)]
+    pub struct WorldUsers {
+        pub world_time: i64,
+        pub users: Vec<i32>,
+        pub profiles: HashMap<i32, PlayerProfile>,
+        /// event id by player id
+        pub events: HashMap<i32, i64>
+    }
+
+    #[derive(Debug, Serialize, Clone, JsonSchema)]
+    pub struct WatchedPlayer {
+        #[serde(flatten)]
+        pub record: PlayerRecord,
+        pub lap: Option<LapSplit>
+    }
+
+    #[derive(Debug, Serialize, Clone, JsonSchema)]
+    pub struct Points {
+        pub config: PointsConfig,
+        pub standings: PointsStandings
+    }
+
+    #[derive(Debug, Serialize, Clone, JsonSchema)]
+    pub struct SegmentLeaderboard {
+        pub segment: Segment,
+        pub leaderboard: Vec<LeaderboardEntry>,
+        pub efforts: Vec<SegmentEffort>
+    }
+
+    /// `data` of actions without result
+    #[derive(Debug, Serialize, Clone, JsonSchema)]
+    pub struct Empty {}
+
+    /// Race results with team classification next to `data`
+    #[derive(Debug, Serialize, Clone, JsonSchema)]
+    pub struct RaceResults {
+        pub result: String,
+        pub data: Vec<RaceResult>,
+        pub teams: Vec<TeamResult>
+    }
 }
 
 pub mod handlers {
+    use std::collections::HashMap;
     use std::sync::{Arc, Mutex};
     use std::iter::FromIterator;
 
+    use warp::Reply;
+    use tokio_stream::StreamExt;
+    use tokio_stream::wrappers::IntervalStream;
+
     use super::*;
 
     pub async fn api_root(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
         let world = world.lock().unwrap();
         Ok(warp::reply::json(&serde_json::json!({
             "result": "ok",
-            "data": {
-                "world_time": world.world_time,
-                "group_to_watch": Vec::<i32>::from_iter(world.group_to_watch.iter())
+            "data": models::WorldState {
+                world_time: world.world_time,
+                group_to_watch: Vec::<i32>::from_iter(world.group_to_watch.iter())
             }
         })))
     }
@@ -38,37 +155,254 @@ pub mod handlers {
         let world = world.lock().unwrap();
         Ok(warp::reply::json(&serde_json::json!({
             "result": "ok",
-            "data": {
-                "world_time": world.world_time,
-                "users": Vec::<i32>::from_iter(world.players_by_id.keys().cloned())
+            "data": models::WorldUsers {
+                world_time: world.world_time,
+                users: Vec::<i32>::from_iter(world.players_by_id.keys().cloned()),
+                profiles: world.profiles.all().clone(),
+                events: world.event_ids().clone()
             }
         })))
     }
 
+    fn group_to_watch_data(world: &World, latest: bool) -> Vec<models::WatchedPlayer> {
+        world.get_group_to_watch_data(latest)
+            .iter()
+            .map(|player| models::WatchedPlayer {
+                record: world.profiles.join(player),
+                lap: world.laps.current_lap(player.id)
+            })
+            .collect()
+    }
+
     pub async fn get_group_to_watch(options: models::WatchOptions, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
         let world = world.lock().unwrap();
-        let mut result = Vec::with_capacity(PLAYER_GROUP_CAPACITY);
-        let latest_time = match &options.latest {
-            Some(_) => world.world_time,
-            _ => world.get_latest_world_time_for_group(&world.group_to_watch)
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": group_to_watch_data(&world, options.latest.is_some())
+        })))
+    }
+
+    /// Server-sent `watch` events with watch group data every second
+    pub async fn stream_group_to_watch(options: models::WatchOptions, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let latest = options.latest.is_some();
+        let events = IntervalStream::new(tokio::time::interval(STREAM_INTERVAL)).map(move |_| {
+            let world = world.lock().unwrap();
+            warp::sse::Event::default()
+                .event("watch")
+                .json_data(serde_json::json!({
+                    "world_time": world.world_time,
+                    "data": group_to_watch_data(&world, latest)
+                }))
+        });
+        Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+    }
+
+    pub async fn get_overlays(overlays: Option<Arc<OverlayTemplates>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let overlays = overlays.ok_or_else(|| ApiError::not_found("Overlays are not configured"))?;
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": overlays.list()
+        })))
+    }
+
+    pub async fn get_overlay(name: String, world: Arc<Mutex<World>>, overlays: Option<Arc<OverlayTemplates>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let overlays = overlays.ok_or_else(|| ApiError::not_found("Overlays are not configured"))?;
+        let template = match overlays.get(&name) {
+            Ok(template) => template,
+            Err(err) => {
+                println!("Overlay template {} error: {}", name, err);
+                return Err(ApiError::not_found(&format!("Overlay template {} not found", name)).into());
+            }
         };
-        for player_id in world.group_to_watch.iter() {
+        let context = {
+            let world = world.lock().unwrap();
+            let riders: Vec<serde_json::Value> = group_to_watch_data(&world, false)
+                .iter()
+                .map(|rider| {
+                    let mut rider = serde_json::to_value(rider).unwrap_or(serde_json::Value::Null);
+                    let name = rider.get("profile")
+                        .and_then(|profile| profile.get("name"))
+                        .cloned()
+                        .or_else(|| rider.get("id").cloned())
+                        .unwrap_or(serde_json::Value::Null);
+                    if let serde_json::Value::Object(record) = &mut rider {
+                        record.insert(String::from("name"), name);
+                    }
+                    rider
+                })
+                .collect();
+            serde_json::json!({
+                "world_time": world.get_latest_world_time_for_group(&world.group_to_watch),
+                "count": riders.len(),
+                "riders": riders
+            })
+        };
+        Ok(warp::reply::html(overlay::live_page(&overlay::render(&template, &context))))
+    }
+
+    pub async fn dashboard() -> Result<impl warp::Reply, warp::Rejection> {
+        Ok(warp::reply::html(DASHBOARD_HTML))
+    }
+
+    pub async fn get_openapi() -> Result<impl warp::Reply, warp::Rejection> {
+        Ok(warp::reply::json(&openapi::document()))
+    }
+
+    pub async fn get_player(player_id: i32, query: models::PlayerTimeQuery, world: Arc<Mutex<World>>, storage: Option<Arc<Mutex<Storage>>>) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_player_id(player_id)?;
+        let mut data = None;
+        {
+            let world = world.lock().unwrap();
             if let Some(player) = world.get_player_data(player_id) {
-                if let Some(data) = match &options.latest {
-                    Some(_) => player.get_latest(),
-                    _ => player.get_at_time(latest_time)
-                } {
-                    result.push(data);
+                data = match query.time {
+                    Some(time) => player.get_at_time(time),
+                    _ => player.get_latest()
+                }.map(|player| serde_json::json!(world.profiles.join(&player)));
+            }
+        }
+        // in-memory history is short, look for older data in database
+        if data.is_none() {
+            if let (Some(time), Some(storage)) = (query.time, storage) {
+                let stored = tokio::task::spawn_blocking(move || {
+                    storage.lock().unwrap().get_at_time(player_id, time).map_err(|err| err.to_string())
+                }).await
+                    .map_err(|err| err.to_string())
+                    .and_then(|result| result)
+                    .map_err(|err| {
+                        println!("Storage error: {}", err);
+                        ApiError::internal("Storage error")
+                    })?;
+                data = stored.map(|stored| world.lock().unwrap().profiles.join_value(player_id, stored));
+            }
+        }
+        let data = data.ok_or_else(|| match query.time {
+            Some(time) => ApiError::not_found(&format!("No data for player {} at {}", player_id, time)),
+            _ => ApiError::not_found(&format!("Player {} not found", player_id))
+        })?;
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": data
+        })))
+    }
+
+    pub async fn get_nearby_players(player_id: i32, query: models::NearbyQuery, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_player_id(player_id)?;
+        let radius = query.radius.unwrap_or(DEFAULT_NEARBY_RADIUS);
+        if !(0. ..=MAX_NEARBY_RADIUS).contains(&radius) {
+            return Err(ApiError::bad_request(&format!("Radius must be from 0 to {} meters", MAX_NEARBY_RADIUS)).into());
+        }
+        let mode = match query.mode {
+            Some(mode) => mode.parse().map_err(|err: String| ApiError::bad_request(&err))?,
+            _ => NearbyMode::Euclidean
+        };
+        let world = world.lock().unwrap();
+        let data = world.find_nearby_players(player_id, radius, mode)
+            .ok_or_else(|| ApiError::not_found(&format!("Player {} not found", player_id)))?;
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": data
+        })))
+    }
+
+    pub async fn get_player_laps(player_id: i32, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_player_id(player_id)?;
+        let world = world.lock().unwrap();
+        let data = world.laps.get(player_id)
+            .ok_or_else(|| ApiError::not_found(&format!("Player {} not found", player_id)))?;
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": data
+        })))
+    }
+
+    pub async fn get_map(query: models::MapQuery, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let defaults = MapOptions::default();
+        let options = MapOptions {
+            width: query.width.unwrap_or(defaults.width),
+            height: query.height.unwrap_or(defaults.height),
+            trails: query.trails.unwrap_or(defaults.trails),
+            labels: query.labels.unwrap_or(defaults.labels),
+            watch_only: query.watch.unwrap_or(defaults.watch_only)
+        };
+        if options.width < MAP_MIN_SIZE || options.height < MAP_MIN_SIZE {
+            return Err(ApiError::bad_request(&format!("Map width and height must be at least {}", MAP_MIN_SIZE)).into());
+        }
+        let world = world.lock().unwrap();
+        Ok(warp::reply::with_header(
+            map::render_svg(&world, &options),
+            "content-type",
+            "image/svg+xml"
+        ))
+    }
+
+    pub async fn metrics(metrics: Arc<Metrics>, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut riders = Vec::with_capacity(PLAYER_GROUP_CAPACITY);
+        {
+            let world = world.lock().unwrap();
+            for player_id in world.group_to_watch.iter() {
+                if let Some(player) = world.get_player_data(player_id).and_then(|player| player.get_latest()) {
+                    let profile = world.profiles.get(player_id);
+                    riders.push(RiderTelemetry {
+                        id: player_id,
+                        name: profile.and_then(|profile| profile.name.clone()),
+                        weight: profile.and_then(|profile| profile.weight),
+                        player
+                    });
                 }
             }
         }
+        let mut output = metrics.render();
+        output.push_str(&render_riders(&riders));
+        Ok(warp::reply::with_header(
+            output,
+            "content-type",
+            "text/plain; version=0.0.4"
+        ))
+    }
+
+    pub async fn get_profiles(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let world = world.lock().unwrap();
         Ok(warp::reply::json(&serde_json::json!({
             "result": "ok",
-            "data": result
+            "data": world.profiles.all()
+        })))
+    }
+
+    pub async fn get_profile(player_id: i32, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_player_id(player_id)?;
+        let world = world.lock().unwrap();
+        let profile = world.profiles.get(player_id)
+            .ok_or_else(|| ApiError::not_found(&format!("Profile {} not found", player_id)))?;
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": profile
+        })))
+    }
+
+    pub async fn update_profile(player_id: i32, profile: PlayerProfile, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_player_id(player_id)?;
+        let mut world = world.lock().unwrap();
+        // saved to profiles file in background
+        let profile = world.profiles.update(player_id, profile).clone();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": profile
+        })))
+    }
+
+    pub async fn delete_profile(player_id: i32, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_player_id(player_id)?;
+        let mut world = world.lock().unwrap();
+        let profile = world.profiles.remove(player_id)
+            .ok_or_else(|| ApiError::not_found(&format!("Profile {} not found", player_id)))?;
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": profile
         })))
     }
 
     pub async fn add_player_to_watch(player: models::PLayerQuery, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_player_id(player.id)?;
         let mut world = world.lock().unwrap();
         world.add_player_to_watch(player.id);
         Ok(warp::reply::json(&serde_json::json!({
@@ -79,6 +413,209 @@ pub mod handlers {
         })))
     }
 
+    pub async fn remove_player_from_watch(player: models::PLayerQuery, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        validate_player_id(player.id)?;
+        let mut world = world.lock().unwrap();
+        world.remove_player_from_watch(player.id);
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": {
+                "id": player.id
+            }
+        })))
+    }
+
+    pub async fn get_drafting(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let world = world.lock().unwrap();
+        let mut result = HashMap::new();
+        for player_id in world.group_to_watch.iter() {
+            if let Some(stats) = world.drafting.get(player_id) {
+                result.insert(player_id, stats.clone());
+            }
+        }
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": result
+        })))
+    }
+
+    pub async fn reset_drafting(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut world = world.lock().unwrap();
+        world.drafting.reset();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": {}
+        })))
+    }
+
+    pub async fn get_attacks(query: models::EventsQuery, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let world = world.lock().unwrap();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": world.attacks.events_since(query.since.unwrap_or(0))
+        })))
+    }
+
+    pub async fn get_race(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let world = world.lock().unwrap();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": world.race.config()
+        })))
+    }
+
+    pub async fn start_race(config: RaceConfig, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut world = world.lock().unwrap();
+        let world_time = world.world_time;
+        world.race.start(config, world_time);
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": world.race.config()
+        })))
+    }
+
+    pub async fn stop_race(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut world = world.lock().unwrap();
+        world.race.stop();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": {}
+        })))
+    }
+
+    pub async fn get_race_results(query: models::ResultsQuery, world: Arc<Mutex<World>>) -> Result<warp::reply::Response, warp::Rejection> {
+        let world = world.lock().unwrap();
+        let results = world.race.results(&world.profiles);
+        if query.format.as_deref() == Some("csv") {
+            return Ok(warp::reply::with_header(
+                results_to_csv(&results),
+                "content-type",
+                "text/csv"
+            ).into_response());
+        }
+        let standings = points::standings(&world.points, &world.segments, &world.race, &world.teams, &world.profiles);
+        let teams = teams::classification(&world.teams, &results, &standings, &world.profiles);
+        Ok(warp::reply::json(&models::RaceResults {
+            result: String::from("ok"),
+            data: results,
+            teams
+        }).into_response())
+    }
+
+    pub async fn get_teams(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let world = world.lock().unwrap();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": world.teams
+        })))
+    }
+
+    pub async fn update_teams(config: TeamConfig, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        config.validate().map_err(|err| ApiError::bad_request(&err))?;
+        let mut world = world.lock().unwrap();
+        world.teams = config;
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": world.teams
+        })))
+    }
+
+    pub async fn get_points(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let world = world.lock().unwrap();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": models::Points {
+                config: world.points.clone(),
+                standings: points::standings(&world.points, &world.segments, &world.race, &world.teams, &world.profiles)
+            }
+        })))
+    }
+
+    pub async fn update_points_config(config: PointsConfig, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut world = world.lock().unwrap();
+        world.points = config;
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": world.points
+        })))
+    }
+
+    pub async fn get_segments(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let world = world.lock().unwrap();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": world.segments.segments()
+        })))
+    }
+
+    pub async fn add_segment(segment: Segment, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut world = world.lock().unwrap();
+        let segment = world.segments.add_segment(segment).clone();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": segment
+        })))
+    }
+
+    pub async fn clear_segments(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut world = world.lock().unwrap();
+        world.segments.clear();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": []
+        })))
+    }
+
+    pub async fn reset_segment_efforts(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut world = world.lock().unwrap();
+        world.segments.reset_efforts();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": {}
+        })))
+    }
+
+    pub async fn get_segment_leaderboard(segment_id: usize, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let world = world.lock().unwrap();
+        let segment = world.segments.get_segment(segment_id)
+            .ok_or_else(|| ApiError::not_found(&format!("Segment {} not found", segment_id)))?;
+        let data = models::SegmentLeaderboard {
+            segment: segment.clone(),
+            leaderboard: world.segments.leaderboard(segment_id, &world.profiles),
+            efforts: world.segments.efforts(segment_id)
+        };
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": data
+        })))
+    }
+
+    pub async fn get_watch_rules(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let world = world.lock().unwrap();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": world.watch_rules
+        })))
+    }
+
+    pub async fn add_watch_rule(rule: WatchRule, world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut world = world.lock().unwrap();
+        world.add_watch_rule(rule);
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": world.watch_rules
+        })))
+    }
+
+    pub async fn clear_watch_rules(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
+        let mut world = world.lock().unwrap();
+        world.clear_watch_rules();
+        Ok(warp::reply::json(&serde_json::json!({
+            "result": "ok",
+            "data": []
+        })))
+    }
+
     pub async fn clear_group_to_watch(world: Arc<Mutex<World>>) -> Result<impl warp::Reply, warp::Rejection> {
         let mut world = world.lock().unwrap();
         world.clear_group_to_watch();
@@ -91,22 +628,59 @@ pub mod handlers {
 
 #[derive(Debug,Clone)]
 pub struct Routes {
-    world: Arc<Mutex<World>>
+    world: Arc<Mutex<World>>,
+    storage: Option<Arc<Mutex<Storage>>>,
+    metrics: Arc<Metrics>,
+    overlays: Option<Arc<OverlayTemplates>>,
+    auth: Arc<Auth>
 }
 
 impl Routes {
 
     pub fn new(world: Arc<Mutex<World>>) -> Self {
         Routes {
-            world: world.clone()
+            world: world.clone(),
+            storage: None,
+            metrics: Arc::new(Metrics::new()),
+            overlays: None,
+            auth: Arc::new(Auth::new())
         }
     }
 
-    pub fn generate(self) -> impl Filter<Extract = (impl warp::Reply), Error = warp::Rejection> + Clone {
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn with_storage(mut self, storage: Arc<Mutex<Storage>>) -> Self {
+        self.storage = Some(storage);
+        self
+    }
+
+    pub fn with_overlays(mut self, overlays: OverlayTemplates) -> Self {
+        self.overlays = Some(Arc::new(overlays));
+        self
+    }
+
+    /// Require API tokens, read only scope for GET requests and admin scope for changes
+    pub fn with_auth(mut self, auth: Auth) -> Self {
+        self.auth = Arc::new(auth);
+        self
+    }
 
-        let world_filter = warp::any().map(move || self.world.clone());
+    pub fn generate(self) -> impl Filter<Extract = (impl warp::Reply), Error = Infallible> + Clone {
 
-        let root_url = warp::path::end()
+        let world = self.world;
+        let world_filter = warp::any().map(move || world.clone());
+        let storage = self.storage;
+        let storage_filter = warp::any().map(move || storage.clone());
+        let metrics = self.metrics;
+        let metrics_filter = warp::any().map(move || metrics.clone());
+        let overlays = self.overlays;
+        let overlays_filter = warp::any().map(move || overlays.clone());
+
+        let root_url = warp::get()
+            .and(warp::path::end())
             .and(world_filter.clone())
             .and_then(handlers::api_root);
 
@@ -125,6 +699,46 @@ impl Routes {
             .and(world_filter.clone())
             .and_then(handlers::add_player_to_watch);
 
+        let remove_player_url = warp::post()
+            .and(warp::path("watch"))
+            .and(warp::path("remove"))
+            .and(warp::path::end())
+            .and(warp::body::json())
+            .and(world_filter.clone())
+            .and_then(handlers::remove_player_from_watch);
+
+        let stream_group_to_watch_url = warp::get()
+            .and(warp::path("watch"))
+            .and(warp::path("stream"))
+            .and(warp::path::end())
+            .and(warp::query::<models::WatchOptions>())
+            .and(world_filter.clone())
+            .and_then(handlers::stream_group_to_watch);
+
+        let overlays_url = warp::get()
+            .and(warp::path("overlay"))
+            .and(warp::path::end())
+            .and(overlays_filter.clone())
+            .and_then(handlers::get_overlays);
+
+        let overlay_url = warp::get()
+            .and(warp::path("overlay"))
+            .and(warp::path::param::<String>())
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and(overlays_filter.clone())
+            .and_then(handlers::get_overlay);
+
+        let dashboard_url = warp::get()
+            .and(warp::path("dashboard"))
+            .and(warp::path::end())
+            .and_then(handlers::dashboard);
+
+        let openapi_url = warp::get()
+            .and(warp::path("openapi.json"))
+            .and(warp::path::end())
+            .and_then(handlers::get_openapi);
+
         let clear_group_to_watch_url = warp::delete()
             .and(warp::path("watch"))
             .and(warp::path("clear"))
@@ -132,16 +746,327 @@ impl Routes {
             .and(world_filter.clone())
             .and_then(handlers::clear_group_to_watch);
 
+        let watch_rules_url = warp::get()
+            .and(warp::path("watch"))
+            .and(warp::path("rules"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::get_watch_rules);
+
+        let add_watch_rule_url = warp::post()
+            .and(warp::path("watch"))
+            .and(warp::path("rules"))
+            .and(warp::path::end())
+            .and(warp::body::json())
+            .and(world_filter.clone())
+            .and_then(handlers::add_watch_rule);
+
+        let clear_watch_rules_url = warp::delete()
+            .and(warp::path("watch"))
+            .and(warp::path("rules"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::clear_watch_rules);
+
+        let drafting_url = warp::get()
+            .and(warp::path("watch"))
+            .and(warp::path("drafting"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::get_drafting);
+
+        let reset_drafting_url = warp::delete()
+            .and(warp::path("watch"))
+            .and(warp::path("drafting"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::reset_drafting);
+
+        let attacks_url = warp::get()
+            .and(warp::path("events"))
+            .and(warp::path("attacks"))
+            .and(warp::path::end())
+            .and(warp::query::<models::EventsQuery>())
+            .and(world_filter.clone())
+            .and_then(handlers::get_attacks);
+
+        let race_url = warp::get()
+            .and(warp::path("race"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::get_race);
+
+        let start_race_url = warp::post()
+            .and(warp::path("race"))
+            .and(warp::path::end())
+            .and(warp::body::json())
+            .and(world_filter.clone())
+            .and_then(handlers::start_race);
+
+        let stop_race_url = warp::delete()
+            .and(warp::path("race"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::stop_race);
+
+        let race_results_url = warp::get()
+            .and(warp::path("race"))
+            .and(warp::path("results"))
+            .and(warp::path::end())
+            .and(warp::query::<models::ResultsQuery>())
+            .and(world_filter.clone())
+            .and_then(handlers::get_race_results);
+
+        let points_url = warp::get()
+            .and(warp::path("race"))
+            .and(warp::path("points"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::get_points);
+
+        let update_points_config_url = warp::put()
+            .and(warp::path("race"))
+            .and(warp::path("points"))
+            .and(warp::path::end())
+            .and(warp::body::json())
+            .and(world_filter.clone())
+            .and_then(handlers::update_points_config);
+
+        let teams_url = warp::get()
+            .and(warp::path("race"))
+            .and(warp::path("teams"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::get_teams);
+
+        let update_teams_url = warp::put()
+            .and(warp::path("race"))
+            .and(warp::path("teams"))
+            .and(warp::path::end())
+            .and(warp::body::json())
+            .and(world_filter.clone())
+            .and_then(handlers::update_teams);
+
+        let segments_url = warp::get()
+            .and(warp::path("segments"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::get_segments);
+
+        let add_segment_url = warp::post()
+            .and(warp::path("segments"))
+            .and(warp::path::end())
+            .and(warp::body::json())
+            .and(world_filter.clone())
+            .and_then(handlers::add_segment);
+
+        let clear_segments_url = warp::delete()
+            .and(warp::path("segments"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::clear_segments);
+
+        let reset_segment_efforts_url = warp::delete()
+            .and(warp::path("segments"))
+            .and(warp::path("efforts"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::reset_segment_efforts);
+
+        let segment_leaderboard_url = warp::get()
+            .and(warp::path("segments"))
+            .and(warp::path::param::<usize>())
+            .and(warp::path("leaderboard"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::get_segment_leaderboard);
+
         let world_users_url = warp::get()
             .and(warp::path("users"))
             .and(warp::path::end())
             .and(world_filter.clone())
             .and_then(handlers::world_users);
 
-        let routes = root_url.or(get_group_to_watch_url)
-            .or(add_player_url).or(clear_group_to_watch_url)
-            .or(world_users_url);
+        let player_url = warp::get()
+            .and(warp::path("players"))
+            .and(warp::path::param::<i32>())
+            .and(warp::path::end())
+            .and(warp::query::<models::PlayerTimeQuery>())
+            .and(world_filter.clone())
+            .and(storage_filter.clone())
+            .and_then(handlers::get_player);
+
+        let nearby_players_url = warp::get()
+            .and(warp::path("players"))
+            .and(warp::path::param::<i32>())
+            .and(warp::path("nearby"))
+            .and(warp::path::end())
+            .and(warp::query::<models::NearbyQuery>())
+            .and(world_filter.clone())
+            .and_then(handlers::get_nearby_players);
+
+        let player_laps_url = warp::get()
+            .and(warp::path("players"))
+            .and(warp::path::param::<i32>())
+            .and(warp::path("laps"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::get_player_laps);
+
+        let map_url = warp::get()
+            .and(warp::path("map.svg"))
+            .and(warp::path::end())
+            .and(warp::query::<models::MapQuery>())
+            .and(world_filter.clone())
+            .and_then(handlers::get_map);
+
+        let metrics_url = warp::get()
+            .and(warp::path("metrics"))
+            .and(warp::path::end())
+            .and(metrics_filter.clone())
+            .and(world_filter.clone())
+            .and_then(handlers::metrics);
+
+        let profiles_url = warp::get()
+            .and(warp::path("profiles"))
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::get_profiles);
+
+        let profile_url = warp::get()
+            .and(warp::path("profiles"))
+            .and(warp::path::param::<i32>())
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::get_profile);
+
+        let update_profile_url = warp::put()
+            .and(warp::path("profiles"))
+            .and(warp::path::param::<i32>())
+            .and(warp::path::end())
+            .and(warp::body::json())
+            .and(world_filter.clone())
+            .and_then(handlers::update_profile);
+
+        let delete_profile_url = warp::delete()
+            .and(warp::path("profiles"))
+            .and(warp::path::param::<i32>())
+            .and(warp::path::end())
+            .and(world_filter.clone())
+            .and_then(handlers::delete_profile);
+
+        // groups are boxed to keep filter types shallow enough for the compiler
+        let watch_routes = get_group_to_watch_url
+            .or(add_player_url).or(remove_player_url).or(clear_group_to_watch_url)
+            .or(stream_group_to_watch_url)
+            .or(watch_rules_url).or(add_watch_rule_url).or(clear_watch_rules_url)
+            .or(drafting_url).or(reset_drafting_url)
+            .boxed();
+
+        let player_routes = world_users_url.or(player_url).or(nearby_players_url).or(player_laps_url)
+            .or(profiles_url).or(profile_url)
+            .or(update_profile_url).or(delete_profile_url)
+            .boxed();
+
+        let race_routes = attacks_url
+            .or(race_url).or(start_race_url).or(stop_race_url).or(race_results_url)
+            .or(points_url).or(update_points_config_url)
+            .or(teams_url).or(update_teams_url)
+            .or(segments_url).or(add_segment_url).or(clear_segments_url)
+            .or(reset_segment_efforts_url).or(segment_leaderboard_url)
+            .boxed();
+
+        let routes = auth::authorize(self.auth)
+            .and(root_url
+            .or(watch_routes)
+            .or(player_routes)
+            .or(race_routes)
+            .or(metrics_url).or(map_url).or(dashboard_url).or(openapi_url)
+            .or(overlays_url).or(overlay_url))
+            .recover(errors::handle_rejection);
 
         routes
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[tokio::test]
+    async fn get_player_storage_fallback() {
+        let player = get_player_instance();
+        let mut old_player = player.clone();
+        old_player.world_time = player.world_time - 60000;
+        let mut storage = Storage::open_in_memory().unwrap();
+        storage.push_players_batch(&[old_player.clone()]).unwrap();
+        let mut world = World::new();
+        world.push_player(player.clone());
+        world.profiles.set(player.id, PlayerProfile { weight: Some(75.), ..PlayerProfile::default() });
+        let routes = Routes::new(Arc::new(Mutex::new(world)))
+            .with_storage(Arc::new(Mutex::new(storage)))
+            .generate();
+
+        let path = format!("/players/{}?time={}", player.id, old_player.world_time);
+        let response = warp::test::request().path(&path).reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(body["data"]["world_time"], old_player.world_time);
+        // same shape as in-memory data
+        assert_eq!(body["data"]["profile"]["weight"], 75.);
+        assert_eq!(body["data"]["wkg"], old_player.power as f64 / 75.);
+
+        let path = format!("/players/{}?time={}", player.id, player.world_time - 120000);
+        let response = warp::test::request().path(&path).reply(&routes).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn get_nearby_players_validation() {
+        let player = get_player_instance();
+        let mut world = World::new();
+        world.push_player(player.clone());
+        let routes = Routes::new(Arc::new(Mutex::new(world))).generate();
+        for query in ["radius=-1", "radius=NaN", "radius=1e12", "mode=bogus"].iter() {
+            let path = format!("/players/{}/nearby?{}", player.id, query);
+            let response = warp::test::request().path(&path).reply(&routes).await;
+            assert_eq!(response.status(), 400, "{}", query);
+        }
+        let path = format!("/players/{}/nearby?radius=5000&mode=road", player.id);
+        let response = warp::test::request().path(&path).reply(&routes).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn get_profile_not_found() {
+        let mut world = World::new();
+        world.profiles.set(5, PlayerProfile { name: Some(String::from("Rider")), ..PlayerProfile::default() });
+        let routes = Routes::new(Arc::new(Mutex::new(world))).generate();
+        let response = warp::test::request().path("/profiles/5").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        let response = warp::test::request().path("/profiles/6").reply(&routes).await;
+        assert_eq!(response.status(), 404);
+        assert_eq!(response.body(), r#"{"error":{"code":404,"message":"Profile 6 not found"},"result":"error"}"#);
+        let response = warp::test::request().method("DELETE").path("/profiles/6").reply(&routes).await;
+        assert_eq!(response.status(), 404);
+        let response = warp::test::request().method("DELETE").path("/profiles/5").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+        let response = warp::test::request().path("/profiles/5").reply(&routes).await;
+        assert_eq!(response.status(), 404);
+    }
+
+    #[tokio::test]
+    async fn get_map_size_validation() {
+        let routes = Routes::new(Arc::new(Mutex::new(World::new()))).generate();
+        for query in ["width=10", "height=39", "width=0&height=0"].iter() {
+            let path = format!("/map.svg?{}", query);
+            let response = warp::test::request().path(&path).reply(&routes).await;
+            assert_eq!(response.status(), 400, "{}", query);
+        }
+        let response = warp::test::request().path("/map.svg?width=100&height=100").reply(&routes).await;
+        assert_eq!(response.status(), 200);
+    }
+}
@@ -0,0 +1,691 @@
+use std::collections::{BTreeMap, HashMap};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::Schema;
+use schemars::JsonSchema;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use crate::attacks::AttackEvent;
+use crate::drafting::DraftStats;
+use crate::laps::LapSplit;
+use crate::profiles::{PlayerProfile, PlayerRecord};
+use crate::race::{Gate, RaceConfig, RaceFinish};
+use crate::segments::{Segment, SegmentBoundary, SegmentKind};
+use crate::spatial::NearbyPlayer;
+use crate::points::PointsConfig;
+use crate::teams::TeamConfig;
+use crate::watch_rules::WatchRule;
+use super::models;
+
+
+const OPENAPI_VERSION: &str = "3.0.3";
+
+/// Sample request bodies shown in the document
+pub trait ApiExample: Serialize + Sized {
+    /// one value per enum variant
+    fn examples() -> Vec<Self>;
+}
+
+/// Fields of `zwift_capture::Player` as serialized in responses, used only for the document
+#[derive(JsonSchema)]
+#[schemars(rename = "Player")]
+pub struct PlayerSchema {
+    pub id: i32,
+    pub world_time: i64,
+    pub group_id: i32,
+    pub distance: i32,
+    pub road_position: i32,
+    pub laps: i32,
+    pub speed: f64,
+    pub heading: i64,
+    pub lean: i32,
+    pub climbing: i32,
+    pub time: i32,
+    pub cadence: i32,
+    pub heartrate: i32,
+    pub power: i32,
+    pub power_up: i32,
+    pub x: f64,
+    pub y: f64
+}
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+type ResponseFn = fn(&mut SchemaGenerator) -> Value;
+
+/// Schema of query and path params, inlined to list every param
+fn inline_schema<T: JsonSchema>(generator: &mut SchemaGenerator) -> Schema {
+    T::json_schema(generator)
+}
+
+/// Reference to schema in `components`
+fn schema_ref<T: JsonSchema>(generator: &mut SchemaGenerator) -> Schema {
+    generator.subschema_for::<T>()
+}
+
+/// `{"result": "ok", "data": T}`
+fn ok_response<T: JsonSchema>(generator: &mut SchemaGenerator) -> Value {
+    json!({
+        "type": "object",
+        "required": ["result", "data"],
+        "properties": {
+            "result": {"type": "string", "enum": ["ok"]},
+            "data": generator.subschema_for::<T>()
+        }
+    })
+}
+
+/// Response of other shape than `ok_response`
+fn response_ref<T: JsonSchema>(generator: &mut SchemaGenerator) -> Value {
+    serde_json::to_value(generator.subschema_for::<T>()).unwrap_or(Value::Null)
+}
+
+fn examples<T: JsonSchema + ApiExample>() -> Value {
+    let name = T::schema_name();
+    let examples: Map<String, Value> = T::examples().iter()
+        .enumerate()
+        .map(|(index, example)| (
+            format!("{}{}", name, index + 1),
+            json!({"value": example})
+        ))
+        .collect();
+    Value::Object(examples)
+}
+
+/// Route of the API for the document, served by filters of `Routes::generate`
+#[derive(Debug,Clone)]
+pub struct ApiOperation {
+    pub method: &'static str,
+    /// path param in braces
+    pub path: &'static str,
+    pub summary: &'static str,
+    pub tag: &'static str,
+    pub content_type: &'static str,
+    /// name and type of the handler argument
+    path_param: Option<(&'static str, SchemaFn)>,
+    query: Option<SchemaFn>,
+    body: Option<(SchemaFn, fn() -> Value)>,
+    response: Option<ResponseFn>
+}
+
+impl ApiOperation {
+    const fn new(method: &'static str, path: &'static str, tag: &'static str, summary: &'static str) -> Self {
+        ApiOperation {
+            method,
+            path,
+            summary,
+            tag,
+            content_type: "application/json",
+            path_param: None,
+            query: None,
+            body: None,
+            response: None
+        }
+    }
+
+    const fn path_param<T: JsonSchema>(mut self, name: &'static str) -> Self {
+        self.path_param = Some((name, inline_schema::<T>));
+        self
+    }
+
+    const fn query<T: JsonSchema>(mut self) -> Self {
+        self.query = Some(inline_schema::<T>);
+        self
+    }
+
+    const fn body<T: JsonSchema + ApiExample>(mut self) -> Self {
+        self.body = Some((schema_ref::<T>, examples::<T>));
+        self
+    }
+
+    /// `data` of JSON response
+    const fn data<T: JsonSchema>(mut self) -> Self {
+        self.response = Some(ok_response::<T>);
+        self
+    }
+
+    /// Whole JSON response
+    const fn response<T: JsonSchema>(mut self) -> Self {
+        self.response = Some(response_ref::<T>);
+        self
+    }
+
+    const fn content_type(mut self, content_type: &'static str) -> Self {
+        self.content_type = content_type;
+        self
+    }
+
+    /// Example request bodies by name
+    pub fn examples(&self) -> Option<Value> {
+        self.body.map(|(_, examples)| examples())
+    }
+
+    /// Path with param replaced by sample value of its type
+    pub fn sample_path(&self, generator: &mut SchemaGenerator) -> String {
+        match self.path_param {
+            Some((name, schema)) => {
+                let value = match serde_json::to_value(schema(generator)).unwrap_or(Value::Null)["type"].as_str() {
+                    Some("string") => "riders",
+                    _ => "1"
+                };
+                self.path.replace(&format!("{{{}}}", name), value)
+            },
+            _ => String::from(self.path)
+        }
+    }
+
+    fn parameters(&self, generator: &mut SchemaGenerator) -> Vec<Value> {
+        let mut parameters = Vec::new();
+        if let Some((name, schema)) = self.path_param {
+            parameters.push(json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": schema(generator)
+            }));
+        }
+        let query = self.query
+            .map(|query| serde_json::to_value(query(generator)).unwrap_or(Value::Null))
+            .unwrap_or(Value::Null);
+        let required = query.get("required").and_then(Value::as_array).cloned().unwrap_or_default();
+        for (name, schema) in query.get("properties").and_then(Value::as_object).into_iter().flatten() {
+            let mut schema = schema.clone();
+            let description = schema.as_object_mut().and_then(|schema| schema.remove("description"));
+            let mut parameter = json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(&json!(name)),
+                "schema": schema
+            });
+            if let Some(description) = description {
+                parameter["description"] = description;
+            }
+            parameters.push(parameter);
+        }
+        parameters
+    }
+
+    fn to_json(&self, generator: &mut SchemaGenerator) -> Value {
+        let response_schema = match self.response {
+            Some(response) => response(generator),
+            _ => json!({"type": "string"})
+        };
+        let mut operation = json!({
+            "summary": self.summary,
+            "tags": [self.tag],
+            "parameters": self.parameters(generator),
+            "responses": {
+                "200": {
+                    "description": "ok",
+                    "content": {self.content_type: {"schema": response_schema}}
+                },
+                "default": {
+                    "description": "error",
+                    "content": {"application/json": {"schema": {"$ref": "#/components/schemas/Error"}}}
+                }
+            }
+        });
+        if let Some((schema, examples)) = self.body {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": {"application/json": {"schema": schema(generator), "examples": examples()}}
+            });
+        }
+        operation
+    }
+}
+
+pub const ROOT: ApiOperation = ApiOperation::new("get", "/", "watch", "Latest world time and watch group ids")
+    .data::<models::WorldState>();
+pub const WORLD_USERS: ApiOperation = ApiOperation::new("get", "/users", "players", "Ids, profiles and events of all riders")
+    .data::<models::WorldUsers>();
+pub const GET_GROUP_TO_WATCH: ApiOperation = ApiOperation::new("get", "/watch", "watch", "Watch group data at synchronized time")
+    .query::<models::WatchOptions>()
+    .data::<Vec<models::WatchedPlayer>>();
+pub const ADD_PLAYER: ApiOperation = ApiOperation::new("post", "/watch/add", "watch", "Add player to watch group")
+    .body::<models::PLayerQuery>()
+    .data::<models::PLayerQuery>();
+pub const REMOVE_PLAYER: ApiOperation = ApiOperation::new("post", "/watch/remove", "watch", "Remove player from watch group")
+    .body::<models::PLayerQuery>()
+    .data::<models::PLayerQuery>();
+pub const CLEAR_GROUP_TO_WATCH: ApiOperation = ApiOperation::new("delete", "/watch/clear", "watch", "Clear watch group")
+    .data::<models::Empty>();
+pub const STREAM_GROUP_TO_WATCH: ApiOperation = ApiOperation::new("get", "/watch/stream", "watch", "Watch group data as server-sent `watch` events every second")
+    .query::<models::WatchOptions>()
+    .content_type("text/event-stream");
+pub const WATCH_RULES: ApiOperation = ApiOperation::new("get", "/watch/rules", "watch", "Rules adding riders to watch group")
+    .data::<Vec<WatchRule>>();
+pub const ADD_WATCH_RULE: ApiOperation = ApiOperation::new("post", "/watch/rules", "watch", "Add watch rule")
+    .body::<WatchRule>()
+    .data::<Vec<WatchRule>>();
+pub const CLEAR_WATCH_RULES: ApiOperation = ApiOperation::new("delete", "/watch/rules", "watch", "Clear watch rules")
+    .data::<Vec<WatchRule>>();
+pub const DRAFTING: ApiOperation = ApiOperation::new("get", "/watch/drafting", "watch", "Drafting time of watch group riders")
+    .data::<HashMap<i32, DraftStats>>();
+pub const RESET_DRAFTING: ApiOperation = ApiOperation::new("delete", "/watch/drafting", "watch", "Reset drafting stats")
+    .data::<models::Empty>();
+pub const ATTACKS: ApiOperation = ApiOperation::new("get", "/events/attacks", "race", "Attacks detected in watch group")
+    .query::<models::EventsQuery>()
+    .data::<Vec<AttackEvent>>();
+pub const RACE: ApiOperation = ApiOperation::new("get", "/race", "race", "Race config and state")
+    .data::<Option<RaceConfig>>();
+pub const START_RACE: ApiOperation = ApiOperation::new("post", "/race", "race", "Start race")
+    .body::<RaceConfig>()
+    .data::<Option<RaceConfig>>();
+pub const STOP_RACE: ApiOperation = ApiOperation::new("delete", "/race", "race", "Stop race")
+    .data::<models::Empty>();
+pub const RACE_RESULTS: ApiOperation = ApiOperation::new("get", "/race/results", "race", "Race results, JSON with team classification under `teams` or CSV")
+    .query::<models::ResultsQuery>()
+    .response::<models::RaceResults>();
+pub const POINTS: ApiOperation = ApiOperation::new("get", "/race/points", "race", "Points standings")
+    .data::<models::Points>();
+pub const UPDATE_POINTS_CONFIG: ApiOperation = ApiOperation::new("put", "/race/points", "race", "Update points scales")
+    .body::<PointsConfig>()
+    .data::<PointsConfig>();
+pub const TEAMS: ApiOperation = ApiOperation::new("get", "/race/teams", "race", "Team config")
+    .data::<TeamConfig>();
+pub const UPDATE_TEAMS: ApiOperation = ApiOperation::new("put", "/race/teams", "race", "Update team config")
+    .body::<TeamConfig>()
+    .data::<TeamConfig>();
+pub const SEGMENTS: ApiOperation = ApiOperation::new("get", "/segments", "segments", "Segments")
+    .data::<Vec<Segment>>();
+pub const ADD_SEGMENT: ApiOperation = ApiOperation::new("post", "/segments", "segments", "Add segment")
+    .body::<Segment>()
+    .data::<Segment>();
+pub const CLEAR_SEGMENTS: ApiOperation = ApiOperation::new("delete", "/segments", "segments", "Remove all segments")
+    .data::<Vec<Segment>>();
+pub const RESET_SEGMENT_EFFORTS: ApiOperation = ApiOperation::new("delete", "/segments/efforts", "segments", "Reset segment efforts")
+    .data::<models::Empty>();
+pub const SEGMENT_LEADERBOARD: ApiOperation = ApiOperation::new("get", "/segments/{id}/leaderboard", "segments", "Segment leaderboard and efforts")
+    .path_param::<usize>("id")
+    .data::<models::SegmentLeaderboard>();
+pub const PLAYER: ApiOperation = ApiOperation::new("get", "/players/{id}", "players", "Latest player data or data at world time")
+    .path_param::<i32>("id")
+    .query::<models::PlayerTimeQuery>()
+    .data::<PlayerRecord>();
+pub const NEARBY_PLAYERS: ApiOperation = ApiOperation::new("get", "/players/{id}/nearby", "players", "Riders near player")
+    .path_param::<i32>("id")
+    .query::<models::NearbyQuery>()
+    .data::<Vec<NearbyPlayer>>();
+pub const PLAYER_LAPS: ApiOperation = ApiOperation::new("get", "/players/{id}/laps", "players", "Lap splits of player")
+    .path_param::<i32>("id")
+    .data::<Vec<LapSplit>>();
+pub const PROFILES: ApiOperation = ApiOperation::new("get", "/profiles", "players", "All player profiles")
+    .data::<HashMap<i32, PlayerProfile>>();
+pub const PROFILE: ApiOperation = ApiOperation::new("get", "/profiles/{id}", "players", "Player profile")
+    .path_param::<i32>("id")
+    .data::<PlayerProfile>();
+pub const UPDATE_PROFILE: ApiOperation = ApiOperation::new("put", "/profiles/{id}", "players", "Update player profile, unset fields are kept")
+    .path_param::<i32>("id")
+    .body::<PlayerProfile>()
+    .data::<PlayerProfile>();
+pub const DELETE_PROFILE: ApiOperation = ApiOperation::new("delete", "/profiles/{id}", "players", "Remove player profile")
+    .path_param::<i32>("id")
+    .data::<PlayerProfile>();
+pub const MAP: ApiOperation = ApiOperation::new("get", "/map.svg", "views", "SVG map of rider positions")
+    .query::<models::MapQuery>()
+    .content_type("image/svg+xml");
+pub const METRICS: ApiOperation = ApiOperation::new("get", "/metrics", "views", "Prometheus metrics")
+    .content_type("text/plain");
+pub const DASHBOARD: ApiOperation = ApiOperation::new("get", "/dashboard", "views", "Dashboard page")
+    .content_type("text/html");
+pub const OVERLAYS: ApiOperation = ApiOperation::new("get", "/overlay", "views", "Overlay template names")
+    .data::<Vec<String>>();
+pub const OVERLAY: ApiOperation = ApiOperation::new("get", "/overlay/{name}", "views", "Overlay page rendered from template")
+    .path_param::<String>("name")
+    .content_type("text/html");
+pub const OPENAPI: ApiOperation = ApiOperation::new("get", "/openapi.json", "views", "This document")
+    .response::<Value>();
+
+/// Every route served by `Routes::generate`, checked against its filters in `openapi_operations_match_routes`
+pub const OPERATIONS: &[ApiOperation] = &[
+    ROOT, WORLD_USERS,
+    GET_GROUP_TO_WATCH, ADD_PLAYER, REMOVE_PLAYER, CLEAR_GROUP_TO_WATCH, STREAM_GROUP_TO_WATCH,
+    WATCH_RULES, ADD_WATCH_RULE, CLEAR_WATCH_RULES, DRAFTING, RESET_DRAFTING,
+    ATTACKS, RACE, START_RACE, STOP_RACE, RACE_RESULTS,
+    POINTS, UPDATE_POINTS_CONFIG, TEAMS, UPDATE_TEAMS,
+    SEGMENTS, ADD_SEGMENT, CLEAR_SEGMENTS, RESET_SEGMENT_EFFORTS, SEGMENT_LEADERBOARD,
+    PLAYER, NEARBY_PLAYERS, PLAYER_LAPS,
+    PROFILES, PROFILE, UPDATE_PROFILE, DELETE_PROFILE,
+    MAP, METRICS, DASHBOARD, OVERLAYS, OVERLAY, OPENAPI
+];
+
+/// OpenAPI document of all routes
+pub fn document() -> Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    let mut paths = Map::new();
+    for operation in OPERATIONS.iter() {
+        let item = paths.entry(operation.path).or_insert_with(|| json!({}));
+        item[operation.method] = operation.to_json(&mut generator);
+    }
+    let mut schemas: BTreeMap<String, Value> = generator.take_definitions().into_iter()
+        .map(|(name, schema)| (name, serde_json::to_value(schema).unwrap_or(Value::Null)))
+        .collect();
+    schemas.insert(String::from("Error"), json!({
+        "type": "object",
+        "required": ["result", "error"],
+        "properties": {
+            "result": {"type": "string", "enum": ["error"]},
+            "error": {
+                "type": "object",
+                "required": ["code", "message"],
+                "properties": {
+                    "code": {"type": "integer"},
+                    "message": {"type": "string"}
+                }
+            }
+        }
+    }));
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": {
+            "title": "zwift_watcher",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Requests are authorized with one of `securitySchemes` only if API is started with `--admin-token`, `--read-token` or `--tokens`"
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas,
+            "securitySchemes": {
+                "bearer": {"type": "http", "scheme": "bearer"},
+                "api_key": {"type": "apiKey", "in": "header", "name": "X-Api-Key"},
+                "access_token": {"type": "apiKey", "in": "query", "name": "access_token"}
+            }
+        }
+    })
+}
+
+
+impl ApiExample for models::PLayerQuery {
+    fn examples() -> Vec<Self> {
+        vec![models::PLayerQuery { id: 108934 }]
+    }
+}
+
+impl ApiExample for PlayerProfile {
+    fn examples() -> Vec<Self> {
+        vec![PlayerProfile {
+            name: Some(String::from("Maksim")),
+            team: Some(String::from("TEAM")),
+            weight: Some(75.),
+            ftp: Some(280),
+            max_heartrate: Some(190),
+            country: Some(String::from("RU"))
+        }]
+    }
+}
+
+impl ApiExample for WatchRule {
+    fn examples() -> Vec<Self> {
+        vec![
+            WatchRule::NameContains { pattern: String::from("[TEAM]") },
+            WatchRule::Team { team: String::from("TEAM") },
+            WatchRule::Group { group_id: 5 }
+        ]
+    }
+}
+
+impl ApiExample for RaceConfig {
+    fn examples() -> Vec<Self> {
+        vec![
+            RaceConfig {
+                name: Some(String::from("Team race")),
+                start_time: Some(199877431690),
+                start_line: None,
+                finish: RaceFinish::Distance { distance: 20000 },
+                group_id: None
+            },
+            RaceConfig {
+                name: Some(String::from("Crit")),
+                start_time: None,
+                start_line: Some(Gate { x1: 1000., y1: -50., x2: 1000., y2: 50. }),
+                finish: RaceFinish::Laps { laps: 3 },
+                group_id: Some(5)
+            }
+        ]
+    }
+}
+
+impl ApiExample for Segment {
+    fn examples() -> Vec<Self> {
+        vec![
+            Segment {
+                id: 0,
+                name: String::from("Sprint"),
+                kind: SegmentKind::Sprint,
+                world_id: Some(1),
+                start: SegmentBoundary::RoadPosition { road_position: 10244300, road_id: Some(5) },
+                end: SegmentBoundary::RoadPosition { road_position: 10300000, road_id: Some(5) }
+            },
+            Segment {
+                id: 0,
+                name: String::from("KOM"),
+                kind: SegmentKind::Kom,
+                world_id: None,
+                start: SegmentBoundary::Gate { gate: Gate { x1: 0., y1: -50., x2: 0., y2: 50. } },
+                end: SegmentBoundary::Gate { gate: Gate { x1: 500., y1: -50., x2: 500., y2: 50. } }
+            }
+        ]
+    }
+}
+
+impl ApiExample for PointsConfig {
+    fn examples() -> Vec<Self> {
+        vec![PointsConfig::default()]
+    }
+}
+
+impl ApiExample for TeamConfig {
+    fn examples() -> Vec<Self> {
+        let mut teams = BTreeMap::new();
+        teams.insert(String::from("TEAM"), vec![108934, 108935]);
+        vec![TeamConfig { best_riders: 3, teams }]
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::BTreeSet;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use crate::World;
+    use crate::tests::get_player_instance;
+    use super::*;
+    use super::super::Routes;
+
+    const METHODS: [&str; 4] = ["get", "post", "put", "delete"];
+
+    fn resolve<'a>(schema: &'a Value, schemas: &'a Value) -> &'a Value {
+        match schema["$ref"].as_str().and_then(|name| name.strip_prefix("#/components/schemas/")) {
+            Some(name) => resolve(&schemas[name], schemas),
+            _ => schema
+        }
+    }
+
+    /// Every value has declared type, every object field is declared
+    fn conforms(value: &Value, schema: &Value, schemas: &Value) -> Result<(), String> {
+        let schema = resolve(schema, schemas);
+        if value.is_null() || schema == &json!({}) || schema == &json!(true) {
+            return Ok(());
+        }
+        for key in ["allOf", "anyOf", "oneOf"].iter() {
+            if let Some(variants) = schema[key].as_array() {
+                let errors: Vec<String> = variants.iter().filter_map(|variant| conforms(value, variant, schemas).err()).collect();
+                let matched = match *key {
+                    "allOf" => errors.is_empty(),
+                    _ => errors.len() < variants.len()
+                };
+                return match matched {
+                    true => Ok(()),
+                    false => Err(errors.join("; "))
+                };
+            }
+        }
+        let type_matches = match schema["type"].as_str() {
+            Some("integer") => value.is_i64() || value.is_u64(),
+            Some("number") => value.is_number(),
+            Some("string") => value.is_string(),
+            Some("boolean") => value.is_boolean(),
+            Some("array") => value.is_array(),
+            Some("object") => value.is_object(),
+            _ => true
+        };
+        if !type_matches {
+            return Err(format!("{} is not {}", value, schema["type"]));
+        }
+        if let Some(items) = value.as_array() {
+            for item in items.iter() {
+                conforms(item, &schema["items"], schemas)?;
+            }
+        }
+        if let Some(fields) = value.as_object() {
+            for (name, field) in fields.iter() {
+                match (schema["properties"].get(name), schema.get("additionalProperties")) {
+                    (Some(property), _) => conforms(field, property, schemas).map_err(|err| format!("{}: {}", name, err))?,
+                    (None, Some(additional)) if additional.is_object() => conforms(field, additional, schemas).map_err(|err| format!("{}: {}", name, err))?,
+                    _ => return Err(format!("{} is not declared", name))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn openapi_document() {
+        let routes: BTreeSet<(&str, &str)> = OPERATIONS.iter()
+            .map(|operation| (operation.method, operation.path))
+            .collect();
+        assert_eq!(routes.len(), OPERATIONS.len());
+
+        let document = document();
+        assert!(document.get("security").is_none());
+        let schemas = &document["components"]["schemas"];
+        let body = &document["paths"]["/watch/add"]["post"]["requestBody"]["content"]["application/json"];
+        assert_eq!(body["schema"]["$ref"], "#/components/schemas/PLayerQuery");
+        assert_eq!(schemas["PLayerQuery"]["properties"]["id"]["type"], "integer");
+        assert_eq!(schemas["PLayerQuery"]["required"], json!(["id"]));
+
+        // optional fields are not required
+        let map_parameters = document["paths"]["/map.svg"]["get"]["parameters"].as_array().unwrap();
+        assert_eq!(map_parameters.len(), 5);
+        assert!(map_parameters.iter().all(|parameter| parameter["required"] == false));
+        assert_eq!(schemas["RaceConfig"]["required"], json!(["finish"]));
+        assert_eq!(schemas["PlayerProfile"].get("required"), None);
+
+        assert_eq!(schemas["TeamConfig"]["properties"]["teams"]["additionalProperties"]["items"]["type"], "integer");
+
+        // tagged enum is one of variants with own fields
+        let rules = schemas["WatchRule"]["oneOf"].as_array().unwrap();
+        assert_eq!(rules.len(), 3);
+        for rule in rules.iter() {
+            assert!(rule["required"].as_array().unwrap().contains(&json!("type")));
+        }
+        assert_eq!(rules[2]["properties"]["group_id"]["type"], "integer");
+
+        // path params typed as handler arguments
+        let player_parameter = &document["paths"]["/players/{id}"]["get"]["parameters"][0];
+        assert_eq!(player_parameter["in"], "path");
+        assert_eq!(player_parameter["schema"]["format"], "int32");
+        assert_eq!(document["paths"]["/segments/{id}/leaderboard"]["get"]["parameters"][0]["schema"]["minimum"], 0.0);
+        assert_eq!(document["paths"]["/overlay/{name}"]["get"]["parameters"][0]["schema"]["type"], "string");
+
+        // response types
+        let player = &document["paths"]["/players/{id}"]["get"]["responses"]["200"]["content"]["application/json"]["schema"];
+        assert_eq!(player["properties"]["data"]["$ref"], "#/components/schemas/PlayerRecord");
+        for field in ["id", "power", "profile", "wkg"].iter() {
+            assert!(schemas["PlayerRecord"]["properties"].get(field).is_some(), "{}", field);
+        }
+        let results = &document["paths"]["/race/results"]["get"]["responses"]["200"]["content"]["application/json"]["schema"];
+        assert_eq!(results["$ref"], "#/components/schemas/RaceResults");
+    }
+
+    #[tokio::test]
+    async fn openapi_operations_match_routes() {
+        let routes = Routes::new(Arc::new(Mutex::new(World::new()))).generate();
+        let mut generator = SchemaSettings::openapi3().into_generator();
+        for operation in OPERATIONS.iter() {
+            let path = operation.sample_path(&mut generator);
+            let mut request = warp::test::request()
+                .method(&operation.method.to_uppercase())
+                .path(&path);
+            if let Some(examples) = operation.examples() {
+                let example = examples.as_object().unwrap().values().next().unwrap();
+                request = request.json(&example["value"]);
+            }
+            if operation.content_type == "text/event-stream" {
+                // event stream doesn't end
+                let response = tokio::time::timeout(Duration::from_millis(100), request.reply(&routes)).await;
+                assert!(response.is_err(), "{} {}", operation.method, operation.path);
+                continue;
+            }
+            let response = request.reply(&routes).await;
+            assert_ne!(response.status(), 405, "{} {}", operation.method, operation.path);
+            // handlers answer 404 for unknown players, segments etc. with own message
+            assert!(
+                !response.body().ends_with(br#""message":"Not found"},"result":"error"}"#),
+                "{} {}", operation.method, operation.path
+            );
+
+            // filters parse path param as declared type
+            if path.ends_with("/1") || path.contains("/1/") {
+                let response = warp::test::request()
+                    .method(&operation.method.to_uppercase())
+                    .path(&path.replace("/1", "/x"))
+                    .reply(&routes).await;
+                // rejected by filters, other routes with the same prefix answer 405
+                let rejected = response.status() == 405
+                    || response.body().ends_with(br#""message":"Not found"},"result":"error"}"#);
+                assert!(rejected, "{} {}", operation.method, operation.path);
+            }
+
+            // methods not in the document are not served
+            for method in METHODS.iter() {
+                if OPERATIONS.iter().any(|other| other.path == operation.path && other.method == *method) {
+                    continue;
+                }
+                let response = warp::test::request()
+                    .method(&method.to_uppercase())
+                    .path(&path)
+                    .reply(&routes).await;
+                assert_eq!(response.status(), 405, "{} {}", method, operation.path);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn openapi_responses_match_schemas() {
+        let mut world = World::new();
+        let player = get_player_instance();
+        world.push_player(player.clone());
+        world.add_player_to_watch(player.id);
+        world.profiles.set(player.id, PlayerProfile::examples().remove(0));
+        world.segments.add_segment(Segment::examples().remove(0));
+        world.race.start(RaceConfig::examples().remove(0), player.world_time);
+        world.add_watch_rule(WatchRule::examples().remove(0));
+        let routes = Routes::new(Arc::new(Mutex::new(world))).generate();
+
+        let document = document();
+        let schemas = &document["components"]["schemas"];
+        let mut generator = SchemaSettings::openapi3().into_generator();
+        let operations = OPERATIONS.iter()
+            .filter(|operation| operation.method == "get" && operation.content_type == "application/json");
+        for operation in operations {
+            let path = operation.sample_path(&mut generator)
+                .replace("/players/1", &format!("/players/{}", player.id))
+                .replace("/profiles/1", &format!("/profiles/{}", player.id))
+                .replace("/segments/1", "/segments/0");
+            let response = warp::test::request().path(&path).reply(&routes).await;
+            if response.status() == 404 {
+                // overlays are not configured
+                continue;
+            }
+            assert_eq!(response.status(), 200, "{}", path);
+            let body: Value = serde_json::from_slice(response.body()).unwrap();
+            let schema = &document["paths"][operation.path]["get"]["responses"]["200"]["content"]["application/json"]["schema"];
+            if let Err(err) = conforms(&body, schema, schemas) {
+                panic!("{}: {}", operation.path, err);
+            }
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use serde::Serialize;
+use schemars::JsonSchema;
+use zwift_capture::Player;
+use crate::server::openapi::PlayerSchema;
+
+
+pub const SPATIAL_CELL_SIZE: f64 = 100.;
+// max side offset from direction of travel for riders on the same road
+pub const ROAD_WIDTH: f64 = 20.;
+/// Largest search radius for nearby riders, meters
+pub const MAX_NEARBY_RADIUS: f64 = 5000.;
+
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum NearbyMode {
+    /// straight line distance
+    Euclidean,
+    /// distance along direction of travel, riders on other roads skipped when roads are known
+    Road
+}
+
+impl FromStr for NearbyMode {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "euclidean" => Ok(NearbyMode::Euclidean),
+            "road" => Ok(NearbyMode::Road),
+            _ => Err(format!("Unknown nearby mode: {}", value))
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone)]
+pub struct NearbyPlayer {
+    pub id: i32,
+    /// straight line distance
+    pub distance: f64,
+    /// distance along direction of travel, positive if ahead
+    pub gap: f64,
+    #[schemars(with = "PlayerSchema")]
+    pub player: Player
+}
+
+/// Signed distance along direction and side offset from `from` to `to`
+pub fn road_gap(from: &Player, to: &Player, direction: (f64, f64)) -> (f64, f64) {
+    let dx = to.x - from.x;
+    let dy = to.y - from.y;
+    let along = dx * direction.0 + dy * direction.1;
+    let side = (dx * direction.1 - dy * direction.0).abs();
+    (along, side)
+}
+
+pub fn euclidean_distance(from: &Player, to: &Player) -> f64 {
+    ((to.x - from.x).powi(2) + (to.y - from.y).powi(2)).sqrt()
+}
+
+
+/// Uniform grid of latest known player positions
+#[derive(Debug)]
+pub struct SpatialIndex {
+    cell_size: f64,
+    cells: HashMap<(i64, i64), HashSet<i32>>,
+    player_cells: HashMap<i32, (i64, i64)>
+}
+
+impl Default for SpatialIndex {
+    fn default() -> Self {
+        SpatialIndex::new(SPATIAL_CELL_SIZE)
+    }
+}
+
+impl SpatialIndex {
+    pub fn new(cell_size: f64) -> Self {
+        SpatialIndex {
+            cell_size,
+            cells: HashMap::new(),
+            player_cells: HashMap::new()
+        }
+    }
+
+    fn cell(&self, x: f64, y: f64) -> (i64, i64) {
+        ((x / self.cell_size).floor() as i64, (y / self.cell_size).floor() as i64)
+    }
+
+    pub fn update(&mut self, player_id: i32, x: f64, y: f64) {
+        let cell = self.cell(x, y);
+        if let Some(&current) = self.player_cells.get(&player_id) {
+            if current == cell {
+                return;
+            }
+            self.remove(player_id);
+        }
+        self.cells.entry(cell).or_insert_with(HashSet::new).insert(player_id);
+        self.player_cells.insert(player_id, cell);
+    }
+
+    pub fn remove(&mut self, player_id: i32) {
+        if let Some(cell) = self.player_cells.remove(&player_id) {
+            if let Some(players) = self.cells.get_mut(&cell) {
+                players.remove(&player_id);
+                if players.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    /// Players in cells covering the circle, may include some outside of radius
+    pub fn query(&self, x: f64, y: f64, radius: f64) -> Vec<i32> {
+        let (min_x, min_y) = self.cell(x - radius, y - radius);
+        let (max_x, max_y) = self.cell(x + radius, y + radius);
+        let mut result = Vec::new();
+        let range_cells = (max_x as i128 - min_x as i128 + 1) * (max_y as i128 - min_y as i128 + 1);
+        if range_cells > self.cells.len() as i128 {
+            // large radius covers mostly empty cells
+            for (&(cell_x, cell_y), players) in self.cells.iter() {
+                if cell_x >= min_x && cell_x <= max_x && cell_y >= min_y && cell_y <= max_y {
+                    result.extend(players.iter().cloned());
+                }
+            }
+            return result;
+        }
+        for cell_x in min_x..=max_x {
+            for cell_y in min_y..=max_y {
+                if let Some(players) = self.cells.get(&(cell_x, cell_y)) {
+                    result.extend(players.iter().cloned());
+                }
+            }
+        }
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.player_cells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.player_cells.is_empty()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn spatial_index_query() {
+        let mut index = SpatialIndex::new(10.);
+        index.update(1, 5., 5.);
+        index.update(2, 25., 5.);
+        index.update(3, 500., 500.);
+        let mut result = index.query(0., 0., 20.);
+        result.sort();
+        assert_eq!(result, vec![1, 2]);
+        index.update(2, 480., 480.);
+        assert_eq!(index.query(0., 0., 20.), vec![1]);
+        index.remove(1);
+        assert!(index.query(0., 0., 20.).is_empty());
+        assert_eq!(index.len(), 2);
+        // fewer cells in range than occupied ones
+        assert_eq!(index.query(485., 485., 1.), vec![2]);
+        // huge radius goes over occupied cells only
+        let mut result = index.query(0., 0., 1e12);
+        result.sort();
+        assert_eq!(result, vec![2, 3]);
+    }
+
+    #[test]
+    fn spatial_road_gap() {
+        let mut from = get_player_instance();
+        from.x = 0.;
+        from.y = 0.;
+        let mut to = from.clone();
+        to.x = -30.;
+        to.y = 4.;
+        let (along, side) = road_gap(&from, &to, (1., 0.));
+        assert_eq!(along, -30.);
+        assert_eq!(side, 4.);
+        assert!((euclidean_distance(&from, &to) - 30.2655).abs() < 0.001);
+    }
+}
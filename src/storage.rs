@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::Value;
+use zwift_capture::Player;
+
+
+pub const STORAGE_BATCH_SIZE: usize = 500;
+/// Player batches queued for writer thread, ~100 per second
+pub const STORAGE_CHANNEL_CAPACITY: usize = 1000;
+const STORAGE_MAX_TIME_DIFF: i64 = 1000;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS samples (
+        player_id INTEGER NOT NULL,
+        world_time INTEGER NOT NULL,
+        group_id INTEGER NOT NULL,
+        distance INTEGER NOT NULL,
+        x REAL NOT NULL,
+        y REAL NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS samples_player_time ON samples (player_id, world_time);
+    CREATE TABLE IF NOT EXISTS sessions (
+        player_id INTEGER NOT NULL,
+        start_world_time INTEGER NOT NULL,
+        end_world_time INTEGER NOT NULL,
+        samples INTEGER NOT NULL,
+        distance INTEGER NOT NULL,
+        avg_power REAL,
+        max_power REAL,
+        avg_heartrate REAL
+    );
+    CREATE INDEX IF NOT EXISTS sessions_player_time ON sessions (player_id, start_world_time);
+";
+
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct SessionSummary {
+    pub player_id: i32,
+    pub start_world_time: i64,
+    pub end_world_time: i64,
+    pub samples: i64,
+    pub distance: i64,
+    pub avg_power: Option<f64>,
+    pub max_power: Option<f64>,
+    pub avg_heartrate: Option<f64>
+}
+
+
+#[derive(Debug)]
+pub struct Storage {
+    connection: Connection,
+    pending: Vec<Player>,
+    batch_size: usize,
+    // first seen world time for every player with open session
+    sessions: HashMap<i32, i64>
+}
+
+impl Storage {
+    pub fn open<P: AsRef<Path>>(path: P) -> rusqlite::Result<Self> {
+        Storage::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Storage::init(Connection::open_in_memory()?)
+    }
+
+    fn init(connection: Connection) -> rusqlite::Result<Self> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Storage {
+            connection,
+            pending: Vec::with_capacity(STORAGE_BATCH_SIZE),
+            batch_size: STORAGE_BATCH_SIZE,
+            sessions: HashMap::new()
+        })
+    }
+
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1);
+    }
+
+    pub fn push_players_batch(&mut self, players: &[Player]) -> rusqlite::Result<usize> {
+        for player in players.iter() {
+            let start = self.sessions.entry(player.id).or_insert(player.world_time);
+            if player.world_time < *start {
+                *start = player.world_time;
+            }
+            self.pending.push(player.clone());
+        }
+        if self.pending.len() >= self.batch_size {
+            return self.flush();
+        }
+        Ok(0)
+    }
+
+    pub fn flush(&mut self) -> rusqlite::Result<usize> {
+        if self.pending.is_empty() {
+            return Ok(0);
+        }
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(
+                "INSERT INTO samples (player_id, world_time, group_id, distance, x, y, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+            )?;
+            for player in self.pending.iter() {
+                let data = serde_json::to_string(player).unwrap_or_default();
+                statement.execute(params![
+                    player.id,
+                    player.world_time,
+                    player.group_id,
+                    player.distance,
+                    player.x,
+                    player.y,
+                    data
+                ])?;
+            }
+        }
+        transaction.commit()?;
+        let count = self.pending.len();
+        self.pending.clear();
+        Ok(count)
+    }
+
+    /// Nearest stored sample of player to requested time
+    pub fn get_at_time(&mut self, player_id: i32, time: i64) -> rusqlite::Result<Option<Value>> {
+        self.flush()?;
+        let row: Option<(i64, String)> = self.connection.query_row(
+            "SELECT world_time, data FROM (
+                SELECT world_time, data FROM (
+                    SELECT world_time, data FROM samples
+                    WHERE player_id = ?1 AND world_time <= ?2
+                    ORDER BY world_time DESC LIMIT 1
+                )
+                UNION ALL
+                SELECT world_time, data FROM (
+                    SELECT world_time, data FROM samples
+                    WHERE player_id = ?1 AND world_time > ?2
+                    ORDER BY world_time ASC LIMIT 1
+                )
+            ) ORDER BY ABS(world_time - ?2) ASC LIMIT 1",
+            params![player_id, time],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        ).optional()?;
+
+        Ok(match row {
+            Some((world_time, data)) if (world_time - time).abs() <= STORAGE_MAX_TIME_DIFF => {
+                serde_json::from_str(&data).ok()
+            },
+            _ => None
+        })
+    }
+
+    pub fn get_range(&mut self, player_id: i32, from_time: i64, to_time: i64) -> rusqlite::Result<Vec<Value>> {
+        self.flush()?;
+        let mut statement = self.connection.prepare_cached(
+            "SELECT data FROM samples
+             WHERE player_id = ?1 AND world_time >= ?2 AND world_time <= ?3
+             ORDER BY world_time ASC"
+        )?;
+        let rows = statement.query_map(params![player_id, from_time, to_time], |row| row.get::<_, String>(0))?;
+        let mut result = Vec::new();
+        for data in rows {
+            if let Ok(value) = serde_json::from_str(&data?) {
+                result.push(value);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Write summary for player session, called when player leaves the world
+    pub fn close_session(&mut self, player_id: i32) -> rusqlite::Result<Option<SessionSummary>> {
+        let start = match self.sessions.remove(&player_id) {
+            Some(start) => start,
+            _ => return Ok(None)
+        };
+        self.flush()?;
+        let summary = self.connection.query_row(
+            "SELECT MIN(world_time), MAX(world_time), COUNT(*), MAX(distance) - MIN(distance),
+                    AVG(json_extract(data, '$.power')), MAX(json_extract(data, '$.power')),
+                    AVG(json_extract(data, '$.heartrate'))
+             FROM samples WHERE player_id = ?1 AND world_time >= ?2",
+            params![player_id, start],
+            |row| Ok(SessionSummary {
+                player_id,
+                start_world_time: row.get::<_, Option<i64>>(0)?.unwrap_or(start),
+                end_world_time: row.get::<_, Option<i64>>(1)?.unwrap_or(start),
+                samples: row.get(2)?,
+                distance: row.get::<_, Option<i64>>(3)?.unwrap_or(0),
+                avg_power: row.get(4)?,
+                max_power: row.get(5)?,
+                avg_heartrate: row.get(6)?
+            })
+        )?;
+        if summary.samples == 0 {
+            return Ok(None);
+        }
+        self.connection.execute(
+            "INSERT INTO sessions (player_id, start_world_time, end_world_time, samples, distance, avg_power, max_power, avg_heartrate)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                summary.player_id,
+                summary.start_world_time,
+                summary.end_world_time,
+                summary.samples,
+                summary.distance,
+                summary.avg_power,
+                summary.max_power,
+                summary.avg_heartrate
+            ]
+        )?;
+        Ok(Some(summary))
+    }
+
+    pub fn close_all_sessions(&mut self) -> rusqlite::Result<usize> {
+        let player_ids: Vec<i32> = self.sessions.keys().cloned().collect();
+        let mut count = 0;
+        for player_id in player_ids {
+            if self.close_session(player_id)?.is_some() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+impl Drop for Storage {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+
+/// Write queued by capture thread
+#[derive(Debug)]
+pub enum StorageWrite {
+    Players(Vec<Player>),
+    CloseSessions(Vec<i32>)
+}
+
+/// Applies queued writes in order until all senders are dropped,
+/// keeps database writes off the capture thread
+pub fn run_writer(storage: Arc<Mutex<Storage>>, receiver: Receiver<StorageWrite>) {
+    for write in receiver {
+        let mut storage = storage.lock().unwrap();
+        let result = match write {
+            StorageWrite::Players(players) => storage.push_players_batch(&players).map(|_| ()),
+            StorageWrite::CloseSessions(player_ids) => player_ids.iter()
+                .try_for_each(|&player_id| storage.close_session(player_id).map(|_| ()))
+        };
+        if let Err(err) = result {
+            println!("Storage error: {}", err);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    fn get_players(count: i64) -> Vec<Player> {
+        let base_player = get_player_instance();
+        (0..count).map(|x| {
+            let mut player = base_player.clone();
+            player.world_time = x * 100;
+            player.distance = (x * 10) as i32;
+            player
+        }).collect()
+    }
+
+    #[test]
+    fn storage_batch_flush() {
+        let mut storage = Storage::open_in_memory().unwrap();
+        storage.set_batch_size(10);
+        assert_eq!(storage.push_players_batch(&get_players(5)).unwrap(), 0);
+        assert_eq!(storage.push_players_batch(&get_players(5)).unwrap(), 10);
+        assert_eq!(storage.flush().unwrap(), 0);
+    }
+
+    #[test]
+    fn storage_get_at_time() {
+        let mut storage = Storage::open_in_memory().unwrap();
+        let players = get_players(20);
+        let player_id = players[0].id;
+        storage.push_players_batch(&players).unwrap();
+        let sample = storage.get_at_time(player_id, 1040).unwrap().unwrap();
+        assert_eq!(sample["world_time"], 1000);
+        let sample = storage.get_at_time(player_id, 1060).unwrap().unwrap();
+        assert_eq!(sample["world_time"], 1100);
+        assert!(storage.get_at_time(player_id, 1900 + STORAGE_MAX_TIME_DIFF + 1).unwrap().is_none());
+        assert_eq!(storage.get_range(player_id, 500, 900).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn storage_close_session() {
+        let mut storage = Storage::open_in_memory().unwrap();
+        let players = get_players(20);
+        let player_id = players[0].id;
+        storage.push_players_batch(&players).unwrap();
+        let summary = storage.close_session(player_id).unwrap().unwrap();
+        assert_eq!(summary.samples, 20);
+        assert_eq!(summary.start_world_time, 0);
+        assert_eq!(summary.end_world_time, 1900);
+        assert_eq!(summary.distance, 190);
+        assert!(storage.close_session(player_id).unwrap().is_none());
+    }
+
+    #[test]
+    fn storage_writer() {
+        let storage = Arc::new(Mutex::new(Storage::open_in_memory().unwrap()));
+        let players = get_players(20);
+        let player_id = players[0].id;
+        let (sender, receiver) = std::sync::mpsc::sync_channel(STORAGE_CHANNEL_CAPACITY);
+        sender.send(StorageWrite::Players(players)).unwrap();
+        sender.send(StorageWrite::CloseSessions(vec![player_id])).unwrap();
+        drop(sender);
+        run_writer(storage.clone(), receiver);
+        let mut storage = storage.lock().unwrap();
+        assert_eq!(storage.get_range(player_id, 0, 1900).unwrap().len(), 20);
+        assert!(storage.close_session(player_id).unwrap().is_none());
+    }
+}
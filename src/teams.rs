@@ -0,0 +1,189 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use crate::points::PointsStandings;
+use crate::profiles::ProfileRegistry;
+use crate::race::RaceResult;
+
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct TeamConfig {
+    /// finish times of best riders summed for team time
+    #[serde(default = "TeamConfig::default_best_riders")]
+    pub best_riders: usize,
+    /// team name to rider ids, takes precedence over profile team
+    #[serde(default)]
+    pub teams: BTreeMap<String, Vec<i32>>
+}
+
+impl TeamConfig {
+    fn default_best_riders() -> usize {
+        3
+    }
+
+    /// Every rider may be listed in one team only
+    pub fn validate(&self) -> Result<(), String> {
+        let mut riders = HashSet::new();
+        for (team, team_riders) in self.teams.iter() {
+            for player_id in team_riders.iter() {
+                if !riders.insert(*player_id) {
+                    return Err(format!("Rider {} is listed in more than one team, last in {}", player_id, team));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn team_of(&self, player_id: i32, profiles: &ProfileRegistry) -> Option<String> {
+        for (team, riders) in self.teams.iter() {
+            if riders.contains(&player_id) {
+                return Some(team.clone());
+            }
+        }
+        profiles.get(player_id).and_then(|profile| profile.team.clone())
+    }
+}
+
+impl Default for TeamConfig {
+    fn default() -> Self {
+        TeamConfig {
+            best_riders: TeamConfig::default_best_riders(),
+            teams: BTreeMap::new()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, JsonSchema, Clone, PartialEq)]
+pub struct TeamResult {
+    pub position: usize,
+    pub team: String,
+    /// sum of best finish times, only when enough riders finished
+    pub time: Option<i64>,
+    pub gap: Option<i64>,
+    /// riders counted for team time
+    pub riders: Vec<i32>,
+    pub finished: usize,
+    pub points: i32
+}
+
+/// Teams with full time first by time, then by number of finished riders and points
+pub fn classification(config: &TeamConfig, results: &[RaceResult], points: &PointsStandings, profiles: &ProfileRegistry) -> Vec<TeamResult> {
+    let mut teams: HashMap<String, TeamResult> = HashMap::new();
+    let new_team = |team: &str| TeamResult {
+        position: 0,
+        team: String::from(team),
+        time: None,
+        gap: None,
+        riders: Vec::new(),
+        finished: 0,
+        points: 0
+    };
+
+    // results are sorted by time already
+    for result in results.iter() {
+        let time = match result.time {
+            Some(time) => time,
+            _ => continue
+        };
+        if let Some(team_name) = config.team_of(result.id, profiles) {
+            let team = teams.entry(team_name.clone()).or_insert_with(|| new_team(&team_name));
+            team.finished += 1;
+            if team.riders.len() < config.best_riders {
+                team.riders.push(result.id);
+                team.time = Some(team.time.unwrap_or(0) + time);
+            }
+        }
+    }
+    for rider in points.riders.iter() {
+        if let Some(team_name) = config.team_of(rider.player_id, profiles) {
+            teams.entry(team_name.clone()).or_insert_with(|| new_team(&team_name)).points += rider.points;
+        }
+    }
+
+    let mut result: Vec<TeamResult> = teams.into_iter()
+        .map(|(_, mut team)| {
+            if team.riders.len() < config.best_riders {
+                team.time = None;
+            }
+            team
+        })
+        .collect();
+    result.sort_by(|a, b| {
+        match (a.time, b.time) {
+            (Some(a_time), Some(b_time)) => a_time.cmp(&b_time),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => b.finished.cmp(&a.finished).then(b.points.cmp(&a.points))
+        }.then(a.team.cmp(&b.team))
+    });
+    let best_time = result.first().and_then(|team| team.time);
+    for (ix, team) in result.iter_mut().enumerate() {
+        team.position = ix + 1;
+        team.gap = team.time.and_then(|time| best_time.map(|best_time| time - best_time));
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::profiles::PlayerProfile;
+    use crate::points::RiderPoints;
+
+    fn race_result(id: i32, time: Option<i64>) -> RaceResult {
+        RaceResult {
+            position: 0,
+            id,
+            name: None,
+            started_at: 0,
+            finished_at: time,
+            time,
+            gap: None,
+            distance: 0,
+            laps: 0
+        }
+    }
+
+    #[test]
+    fn team_classification() {
+        let mut profiles = ProfileRegistry::new();
+        for (id, team) in [(1, "A"), (2, "B"), (3, "A"), (4, "B"), (5, "A")].iter() {
+            profiles.set(*id, PlayerProfile { team: Some(String::from(*team)), ..PlayerProfile::default() });
+        }
+        let mut config = TeamConfig::default();
+        config.best_riders = 2;
+        // rider 6 without profile in team C, rider 5 moved to team C
+        config.teams.insert(String::from("C"), vec![5, 6]);
+        assert_eq!(config.team_of(5, &profiles), Some(String::from("C")));
+        assert!(config.validate().is_ok());
+        config.teams.insert(String::from("D"), vec![7, 6]);
+        assert!(config.validate().is_err());
+        config.teams.remove("D");
+
+        let results = vec![
+            race_result(2, Some(1000)),
+            race_result(1, Some(1100)),
+            race_result(5, Some(1150)),
+            race_result(3, Some(1200)),
+            race_result(4, Some(1500)),
+            race_result(6, None)
+        ];
+        let points = PointsStandings {
+            riders: vec![RiderPoints { position: 1, player_id: 6, name: None, team: None, points: 5 }],
+            ..PointsStandings::default()
+        };
+        let teams = classification(&config, &results, &points, &profiles);
+        let summary: Vec<(&str, Option<i64>, usize, i32)> = teams.iter()
+            .map(|team| (team.team.as_str(), team.time, team.finished, team.points))
+            .collect();
+        assert_eq!(summary, vec![
+            ("A", Some(2300), 2, 0),
+            ("B", Some(2500), 2, 0),
+            ("C", None, 1, 5)
+        ]);
+        assert_eq!(teams[1].gap, Some(200));
+        assert_eq!(teams[0].riders, vec![1, 3]);
+    }
+}
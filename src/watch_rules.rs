@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+use schemars::JsonSchema;
+use zwift_capture::Player;
+use crate::profiles::PlayerProfile;
+
+
+/// Rule to add riders to watch group automatically
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WatchRule {
+    /// profile name contains pattern, case insensitive, e.g. team tag `[TEAM]`
+    NameContains { pattern: String },
+    /// profile team equals, case insensitive
+    Team { team: String },
+    Group { group_id: i32 }
+}
+
+impl WatchRule {
+    pub fn matches(&self, player: &Player, profile: Option<&PlayerProfile>) -> bool {
+        match self {
+            WatchRule::NameContains { pattern } => {
+                match profile.and_then(|profile| profile.name.as_ref()) {
+                    Some(name) => name.to_lowercase().contains(&pattern.to_lowercase()),
+                    None => false
+                }
+            },
+            WatchRule::Team { team } => {
+                match profile.and_then(|profile| profile.team.as_ref()) {
+                    Some(player_team) => player_team.to_lowercase() == team.to_lowercase(),
+                    None => false
+                }
+            },
+            WatchRule::Group { group_id } => player.group_id == *group_id
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::tests::get_player_instance;
+
+    #[test]
+    fn watch_rule_matches() {
+        let player = get_player_instance();
+        let profile = PlayerProfile {
+            name: Some(String::from("Rider [TEAM]")),
+            team: Some(String::from("Team")),
+            ..PlayerProfile::default()
+        };
+        let name_rule = WatchRule::NameContains { pattern: String::from("[team]") };
+        assert!(name_rule.matches(&player, Some(&profile)));
+        assert!(!name_rule.matches(&player, None));
+        assert!(WatchRule::Team { team: String::from("TEAM") }.matches(&player, Some(&profile)));
+        assert!(WatchRule::Group { group_id: player.group_id }.matches(&player, None));
+        assert!(!WatchRule::Group { group_id: player.group_id + 1 }.matches(&player, None));
+    }
+}